@@ -0,0 +1,145 @@
+// Routes actions on desktop notifications back to the device they came from.
+// A single task listens for the notification server's signals, and hands each
+// one to the task of the device that sent the notification, which performs the
+// action in between its other control point writes.
+
+use crate::SessionCommand;
+use futures::stream::StreamExt;
+use notify_rust::CloseReason;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+
+pub enum DesktopEvent {
+    Action(u32, String),
+    Closed(u32, CloseReason),
+}
+
+pub enum DispatcherMessage {
+    Register(u32, mpsc::Sender<SessionCommand>),
+    Unregister(u32),
+}
+
+// a device's side of the dispatcher
+pub struct DesktopActions {
+    dispatcher_tx: mpsc::UnboundedSender<DispatcherMessage>,
+    session_tx: mpsc::Sender<SessionCommand>,
+    // desktop notification ID to notification UID
+    notification_uids: HashMap<u32, u32>,
+}
+
+impl DesktopActions {
+    pub fn new(
+        dispatcher_tx: mpsc::UnboundedSender<DispatcherMessage>,
+        session_tx: mpsc::Sender<SessionCommand>,
+    ) -> Self {
+        DesktopActions {
+            dispatcher_tx,
+            session_tx,
+            notification_uids: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, notif_id: u32, notification_uid: u32) {
+        self.notification_uids.insert(notif_id, notification_uid);
+        // only fails if the dispatcher has stopped, and then there are no actions anyway
+        let _ = self.dispatcher_tx.send(DispatcherMessage::Register(
+            notif_id,
+            self.session_tx.clone(),
+        ));
+    }
+
+    // a notification is done with once it's acted on or closed, and the
+    // dispatcher forgets it by itself
+    pub fn closed(&mut self, notif_id: u32) -> Option<u32> {
+        self.notification_uids.remove(&notif_id)
+    }
+
+    pub fn forget(&mut self, notification_uid: u32) {
+        let dispatcher_tx = &self.dispatcher_tx;
+        self.notification_uids.retain(|notif_id, uid| {
+            if *uid == notification_uid {
+                let _ = dispatcher_tx.send(DispatcherMessage::Unregister(*notif_id));
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+impl Drop for DesktopActions {
+    fn drop(&mut self) {
+        for notif_id in self.notification_uids.keys() {
+            let _ = self
+                .dispatcher_tx
+                .send(DispatcherMessage::Unregister(*notif_id));
+        }
+    }
+}
+
+pub fn parse_signal(signal: &zbus::Message) -> Option<DesktopEvent> {
+    let header = signal.header();
+    match header.member() {
+        Some(name) if name == "ActionInvoked" => signal
+            .body()
+            .deserialize::<(u32, String)>()
+            .ok()
+            .map(|(notif_id, action)| DesktopEvent::Action(notif_id, action)),
+        Some(name) if name == "NotificationClosed" => signal
+            .body()
+            .deserialize::<(u32, u32)>()
+            .ok()
+            .map(|(notif_id, reason)| DesktopEvent::Closed(notif_id, reason.into())),
+        _ => None,
+    }
+}
+
+pub async fn action_dispatcher(
+    mut dispatcher_rx: mpsc::UnboundedReceiver<DispatcherMessage>,
+) -> zbus::Result<()> {
+    let connection = zbus::Connection::session().await?;
+    let rule = zbus::MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .interface("org.freedesktop.Notifications")?
+        .build();
+    let mut signals = zbus::MessageStream::for_match_rule(rule, &connection, None).await?;
+    let mut sessions: HashMap<u32, mpsc::Sender<SessionCommand>> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            message = dispatcher_rx.recv() => {
+                match message {
+                    Some(DispatcherMessage::Register(notif_id, session_tx)) => {
+                        sessions.insert(notif_id, session_tx);
+                    }
+                    Some(DispatcherMessage::Unregister(notif_id)) => {
+                        sessions.remove(&notif_id);
+                    }
+                    // every device has gone away
+                    None => break,
+                }
+            },
+            Some(signal) = signals.next() => {
+                let event = match signal {
+                    Ok(signal) => parse_signal(&signal),
+                    Err(e) => {
+                        eprintln!("error receiving notification signal: {e:?}");
+                        None
+                    }
+                };
+                let notif_id = match &event {
+                    Some(DesktopEvent::Action(notif_id, _)) => *notif_id,
+                    Some(DesktopEvent::Closed(notif_id, _)) => *notif_id,
+                    None => continue,
+                };
+                // after an action the server still says the notification closed,
+                // which mustn't count as dismissing it
+                if let (Some(session_tx), Some(event)) = (sessions.remove(&notif_id), event) {
+                    // fails only if that device's task has ended
+                    let _ = session_tx.send(SessionCommand::DesktopEvent(event)).await;
+                }
+            },
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,163 @@
+// Advertising through BlueZ that we want ANCS, for --advertise. iPhones only
+// list computers that solicit it, and then connect to them from their side,
+// which tends to work better than connecting from here.
+
+use std::collections::{HashMap, HashSet};
+use zbus::zvariant::{ObjectPath, OwnedValue};
+
+struct Advertisement;
+
+#[zbus::interface(name = "org.bluez.LEAdvertisement1")]
+impl Advertisement {
+    // BlueZ gave up on it, e.g. because the adapter went away
+    fn release(&self) {}
+
+    #[zbus(property, name = "Type")]
+    fn kind(&self) -> String {
+        "peripheral".to_string()
+    }
+
+    #[zbus(property, name = "SolicitUUIDs")]
+    fn solicit_uuids(&self) -> Vec<String> {
+        vec![ancs::APPLE_NOTIFICATION_CENTER_SERVICE_UUID.to_string()]
+    }
+
+    // the adapter's name, which is what the phone lists
+    #[zbus(property)]
+    fn includes(&self) -> Vec<String> {
+        vec!["local-name".to_string()]
+    }
+
+    #[zbus(property)]
+    fn discoverable(&self) -> bool {
+        true
+    }
+}
+
+pub struct Advertiser {
+    system: zbus::Connection,
+    destination: String,
+    // the adapters it's registered with
+    advertising: HashSet<String>,
+    // the adapters it couldn't register with, so the error is only shown once
+    failing: HashSet<String>,
+}
+
+impl Advertiser {
+    pub fn new(system: zbus::Connection, destination: &str) -> Self {
+        Advertiser {
+            system,
+            destination: destination.to_string(),
+            advertising: HashSet::new(),
+            failing: HashSet::new(),
+        }
+    }
+
+    fn object_path(adapter_name: &str) -> String {
+        format!("/net/boatcake/ios_notif_forward/advertisement/{adapter_name}")
+    }
+
+    async fn manager(&self, adapter_name: &str) -> zbus::Result<zbus::Proxy<'_>> {
+        zbus::proxy::Builder::new(&self.system)
+            .destination(self.destination.as_str())?
+            .path(format!("/org/bluez/{adapter_name}"))?
+            .interface("org.bluez.LEAdvertisingManager1")?
+            .cache_properties(zbus::proxy::CacheProperties::No)
+            .build()
+            .await
+    }
+
+    pub async fn start(&self, adapter_name: &str) -> zbus::Result<()> {
+        let path = Self::object_path(adapter_name);
+        self.system
+            .object_server()
+            .at(path.as_str(), Advertisement)
+            .await?;
+        let options: HashMap<String, OwnedValue> = HashMap::new();
+        let registered = match self.manager(adapter_name).await {
+            Ok(manager) => {
+                manager
+                    .call::<_, _, ()>(
+                        "RegisterAdvertisement",
+                        &(ObjectPath::try_from(path.as_str())?, options),
+                    )
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if registered.is_err() {
+            self.system
+                .object_server()
+                .remove::<Advertisement, _>(path.as_str())
+                .await?;
+        }
+        registered
+    }
+
+    pub async fn stop(&self, adapter_name: &str) -> zbus::Result<()> {
+        let path = Self::object_path(adapter_name);
+        if self
+            .system
+            .object_server()
+            .interface::<_, Advertisement>(path.as_str())
+            .await
+            .is_err()
+        {
+            // it never started
+            return Ok(());
+        }
+        // fails if the adapter is already gone, which unregisters it anyway
+        let unregistered = match self.manager(adapter_name).await {
+            Ok(manager) => {
+                manager
+                    .call::<_, _, ()>(
+                        "UnregisterAdvertisement",
+                        &(ObjectPath::try_from(path.as_str())?,),
+                    )
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        self.system
+            .object_server()
+            .remove::<Advertisement, _>(path.as_str())
+            .await?;
+        unregistered
+    }
+
+    // Stops advertising on the adapters that went, and starts on the ones that
+    // came or couldn't be started on before, e.g. because they weren't powered yet.
+    pub async fn update<'a>(&mut self, adapters: impl IntoIterator<Item = &'a String>) {
+        let adapters: HashSet<&String> = adapters.into_iter().collect();
+        let gone: Vec<String> = self
+            .advertising
+            .iter()
+            .filter(|name| !adapters.contains(name))
+            .cloned()
+            .collect();
+        for name in gone {
+            if let Err(e) = self.stop(&name).await {
+                eprintln!("error stopping advertising on {name}: {e:?}");
+            }
+            self.advertising.remove(&name);
+        }
+        self.failing.retain(|name| adapters.contains(name));
+        for name in adapters {
+            if self.advertising.contains(name) {
+                continue;
+            }
+            match self.start(name).await {
+                Ok(()) => {
+                    println!("advertising on {name}");
+                    self.failing.remove(name);
+                    self.advertising.insert(name.clone());
+                }
+                Err(e) => {
+                    if self.failing.insert(name.clone()) {
+                        eprintln!("error advertising on {name}: {e:?}");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,3 @@
+// Shared with the package tool, which includes this file. Windows attributes
+// notifications to the Start menu shortcut the installer gives this ID.
+pub const APP_USER_MODEL_ID: &str = "net.boatcake.ios-notif-forward";
@@ -0,0 +1,172 @@
+// Starting the app on login, with the same arguments it was started with this time.
+
+use std::io;
+
+#[cfg(target_os = "macos")]
+macro_rules! LAUNCH_AGENT_FMT { () => { "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">
+<plist version=\"1.0\">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+{}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+</dict>
+</plist>
+" }; }
+
+fn command_line() -> io::Result<Vec<String>> {
+    // inside an AppImage, the executable is in a mount that's gone by the next login
+    let exe = match std::env::var_os("APPIMAGE") {
+        Some(appimage) if cfg!(all(unix, not(target_os = "macos"))) => appimage.into(),
+        _ => std::env::current_exe()?,
+    };
+    let mut args = vec![exe.to_string_lossy().into_owned()];
+    args.extend(std::env::args().skip(1));
+    Ok(args)
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn autostart_path() -> std::path::PathBuf {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(config_dir) => std::path::PathBuf::from(config_dir),
+        None => std::path::Path::new(&std::env::var_os("HOME").unwrap_or_default()).join(".config"),
+    };
+    config_dir
+        .join("autostart")
+        .join(concat!(env!("CARGO_PKG_NAME"), ".desktop"))
+}
+
+// quoting rules from the Desktop Entry specification
+#[cfg(all(unix, not(target_os = "macos")))]
+fn quote_exec_arg(arg: &str) -> String {
+    // field codes
+    let arg = arg.replace('%', "%%");
+    if arg.is_empty()
+        || arg.contains(|c: char| c.is_whitespace() || "\"'\\><~|&;$*?#()`".contains(c))
+    {
+        let mut quoted = String::from("\"");
+        for c in arg.chars() {
+            if "\"`$\\".contains(c) {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+        quoted
+    } else {
+        arg
+    }
+}
+
+// a Flatpak's config directory and executable path only make sense inside the
+// sandbox, so the desktop's autostart would never find it
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn is_available() -> bool {
+    !std::path::Path::new("/.flatpak-info").exists()
+}
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+pub fn is_available() -> bool {
+    true
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn is_enabled() -> bool {
+    autostart_path().exists()
+}
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn set_enabled(enabled: bool) -> io::Result<()> {
+    let path = autostart_path();
+    if enabled {
+        let exec = command_line()?
+            .iter()
+            .map(|arg| quote_exec_arg(arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+            // string values are unescaped before the arguments are unquoted
+            .replace('\\', "\\\\");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(
+            path,
+            format!(
+                concat!(DESKTOP_ENTRY_FMT!(), "X-GNOME-Autostart-enabled=true\n"),
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_NAME"),
+                exec
+            ),
+        )
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+#[cfg(target_os = "macos")]
+const LAUNCH_AGENT_LABEL: &str = concat!("net.boatcake.", env!("CARGO_PKG_NAME"));
+
+#[cfg(target_os = "macos")]
+fn launch_agent_path() -> std::path::PathBuf {
+    std::path::Path::new(&std::env::var_os("HOME").unwrap_or_default())
+        .join("Library")
+        .join("LaunchAgents")
+        .join(format!("{LAUNCH_AGENT_LABEL}.plist"))
+}
+
+#[cfg(target_os = "macos")]
+pub fn is_enabled() -> bool {
+    launch_agent_path().exists()
+}
+#[cfg(target_os = "macos")]
+pub fn set_enabled(enabled: bool) -> io::Result<()> {
+    let path = launch_agent_path();
+    if enabled {
+        let arguments: String = command_line()?
+            .iter()
+            .map(|arg| {
+                let escaped = arg
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;");
+                format!("        <string>{escaped}</string>\n")
+            })
+            .collect();
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(
+            path,
+            format!(LAUNCH_AGENT_FMT!(), LAUNCH_AGENT_LABEL, arguments),
+        )
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+#[cfg(windows)]
+fn run_key() -> io::Result<winreg::RegKey> {
+    winreg::RegKey::predef(winreg::enums::HKEY_CURRENT_USER).open_subkey_with_flags(
+        r"Software\Microsoft\Windows\CurrentVersion\Run",
+        winreg::enums::KEY_QUERY_VALUE | winreg::enums::KEY_SET_VALUE,
+    )
+}
+
+#[cfg(windows)]
+pub fn is_enabled() -> bool {
+    run_key()
+        .and_then(|key| key.get_value::<String, _>(env!("CARGO_PKG_NAME")))
+        .is_ok()
+}
+#[cfg(windows)]
+pub fn set_enabled(enabled: bool) -> io::Result<()> {
+    let key = run_key()?;
+    if enabled {
+        let command_line = command_line()?
+            .iter()
+            .map(|arg| format!("\"{arg}\""))
+            .collect::<Vec<_>>()
+            .join(" ");
+        key.set_value(env!("CARGO_PKG_NAME"), &command_line)
+    } else {
+        key.delete_value(env!("CARGO_PKG_NAME"))
+    }
+}
@@ -0,0 +1,23 @@
+// What the parts that talk to BlueZ over D-Bus themselves share, for the
+// things btleplug doesn't do.
+
+pub const BLUEZ: &str = "org.bluez";
+
+// peripheral IDs are BlueZ object paths without this
+pub const PATH_PREFIX: &str = "/org/bluez/";
+
+// every object BlueZ has, with their interfaces and properties
+pub async fn managed_objects(
+    system: &zbus::Connection,
+    destination: &str,
+) -> zbus::Result<zbus::fdo::ManagedObjects> {
+    let objects = zbus::fdo::ObjectManagerProxy::builder(system)
+        .destination(destination)?
+        .path("/")?
+        .cache_properties(zbus::proxy::CacheProperties::No)
+        .build()
+        .await?
+        .get_managed_objects()
+        .await?;
+    Ok(objects)
+}
@@ -0,0 +1,272 @@
+// Control channel between the running app and command-line invocations of it.
+// Requests are a single line, replies are "ok" followed by tab-separated
+// records, or a single "error\t<message>" line.
+
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+
+pub enum ControlRequest {
+    List,
+    Dismiss(u32),
+    Accept(u32),
+    Pause(Option<Duration>),
+    Resume,
+    Status,
+}
+
+pub struct ControlMessage {
+    pub request: ControlRequest,
+    pub reply: oneshot::Sender<Result<String, String>>,
+}
+
+#[derive(Clone, Copy)]
+pub enum Pause {
+    Running,
+    Until(Instant),
+    Indefinite,
+}
+
+impl Pause {
+    pub fn is_paused(&self) -> bool {
+        match self {
+            Pause::Running => false,
+            Pause::Until(until) => *until > Instant::now(),
+            Pause::Indefinite => true,
+        }
+    }
+}
+
+impl ControlRequest {
+    fn to_line(&self) -> String {
+        match self {
+            ControlRequest::List => "list".to_string(),
+            ControlRequest::Dismiss(uid) => format!("dismiss {uid}"),
+            ControlRequest::Accept(uid) => format!("accept {uid}"),
+            ControlRequest::Pause(Some(duration)) => format!("pause {}", duration.as_secs()),
+            ControlRequest::Pause(None) => "pause".to_string(),
+            ControlRequest::Resume => "resume".to_string(),
+            ControlRequest::Status => "status".to_string(),
+        }
+    }
+
+    fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let argument = words.next();
+        let uid = || {
+            argument
+                .and_then(|a| a.parse::<u32>().ok())
+                .ok_or_else(|| format!("{command} needs a notification UID"))
+        };
+        match command {
+            "list" => Ok(ControlRequest::List),
+            "dismiss" => Ok(ControlRequest::Dismiss(uid()?)),
+            "accept" => Ok(ControlRequest::Accept(uid()?)),
+            "pause" => match argument {
+                Some(secs) => secs
+                    .parse::<u64>()
+                    .map(|secs| ControlRequest::Pause(Some(Duration::from_secs(secs))))
+                    .map_err(|_| format!("invalid pause duration {secs:?}")),
+                None => Ok(ControlRequest::Pause(None)),
+            },
+            "resume" => Ok(ControlRequest::Resume),
+            "status" => Ok(ControlRequest::Status),
+            _ => Err(format!("unknown command {command:?}")),
+        }
+    }
+}
+
+// accepts plain seconds, or a number suffixed with s, m, h or d
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration {value:?}"))?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown duration unit {unit:?}")),
+    };
+    number
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration {value:?} is too long"))
+}
+
+async fn handle_client<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    requests: mpsc::Sender<ControlMessage>,
+) {
+    let (read_half, mut write_half) = tokio::io::split(stream);
+    let mut line = String::new();
+    if let Err(e) = BufReader::new(read_half).read_line(&mut line).await {
+        eprintln!("error reading control request: {e:?}");
+        return;
+    }
+    let result = match ControlRequest::parse(line.trim()) {
+        Ok(request) => {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            let message = ControlMessage {
+                request,
+                reply: reply_tx,
+            };
+            if requests.send(message).await.is_ok() {
+                reply_rx
+                    .await
+                    .unwrap_or_else(|_| Err("shutting down".to_string()))
+            } else {
+                Err("shutting down".to_string())
+            }
+        }
+        Err(e) => Err(e),
+    };
+    let out = match result {
+        Ok(body) => format!("ok\n{body}"),
+        Err(e) => format!("error\t{e}\n"),
+    };
+    if let Err(e) = write_half.write_all(out.as_bytes()).await {
+        eprintln!("error replying to control request: {e:?}");
+    }
+}
+
+#[cfg(unix)]
+fn socket_path() -> std::path::PathBuf {
+    if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        std::path::Path::new(&runtime_dir).join(concat!(env!("CARGO_PKG_NAME"), ".sock"))
+    } else {
+        // the temporary directory is shared, so keep users apart
+        let user = std::env::var("USER").unwrap_or_default();
+        std::env::temp_dir().join(format!("{}-{user}.sock", env!("CARGO_PKG_NAME")))
+    }
+}
+
+#[cfg(windows)]
+fn pipe_name() -> String {
+    let user = std::env::var("USERNAME").unwrap_or_default();
+    format!(r"\\.\pipe\{}-{user}", env!("CARGO_PKG_NAME"))
+}
+
+// Only one instance can hold the control channel, so it doubles as the
+// single-instance lock. Binding fails with AddrInUse if another instance has it.
+#[cfg(unix)]
+pub struct ControlListener {
+    listener: tokio::net::UnixListener,
+    // held for as long as the socket is, see bind
+    _lock: std::fs::File,
+}
+#[cfg(windows)]
+pub struct ControlListener(tokio::net::windows::named_pipe::NamedPipeServer);
+
+#[cfg(unix)]
+pub async fn bind() -> std::io::Result<ControlListener> {
+    let path = socket_path();
+    // Without this, two instances starting at once after a crash could both
+    // find the socket stale, and the second would remove the first's. The lock
+    // goes away with the process, so unlike the socket it can't be left behind.
+    let lock = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.with_extension("lock"))?;
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => return Err(std::io::ErrorKind::AddrInUse.into()),
+        Err(std::fs::TryLockError::Error(e)) => return Err(e),
+    }
+    let listener = match tokio::net::UnixListener::bind(&path) {
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            if tokio::net::UnixStream::connect(&path).await.is_ok() {
+                return Err(e);
+            }
+            // nobody is listening, so it was left behind by an instance that crashed
+            std::fs::remove_file(&path)?;
+            tokio::net::UnixListener::bind(&path)?
+        }
+        result => result?,
+    };
+    Ok(ControlListener {
+        listener,
+        _lock: lock,
+    })
+}
+#[cfg(windows)]
+pub async fn bind() -> std::io::Result<ControlListener> {
+    tokio::net::windows::named_pipe::ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(pipe_name())
+        .map(ControlListener)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::PermissionDenied => std::io::ErrorKind::AddrInUse.into(),
+            _ => e,
+        })
+}
+
+#[cfg(unix)]
+impl Drop for ControlListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(socket_path());
+    }
+}
+
+#[cfg(unix)]
+pub async fn serve(
+    listener: ControlListener,
+    requests: mpsc::Sender<ControlMessage>,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.listener.accept().await?;
+        tokio::spawn(handle_client(stream, requests.clone()));
+    }
+}
+#[cfg(windows)]
+pub async fn serve(
+    listener: ControlListener,
+    requests: mpsc::Sender<ControlMessage>,
+) -> std::io::Result<()> {
+    let name = pipe_name();
+    let mut server = listener.0;
+    loop {
+        server.connect().await?;
+        let client = server;
+        server = tokio::net::windows::named_pipe::ServerOptions::new().create(&name)?;
+        tokio::spawn(handle_client(client, requests.clone()));
+    }
+}
+
+#[cfg(unix)]
+async fn connect() -> std::io::Result<tokio::net::UnixStream> {
+    tokio::net::UnixStream::connect(socket_path()).await
+}
+#[cfg(windows)]
+async fn connect() -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    tokio::net::windows::named_pipe::ClientOptions::new().open(pipe_name())
+}
+
+// the outer error is for failing to reach the running app,
+// the inner one is an error reported by it
+pub async fn send(request: ControlRequest) -> std::io::Result<Result<String, String>> {
+    let (read_half, mut write_half) = tokio::io::split(connect().await?);
+    write_half
+        .write_all(format!("{}\n", request.to_line()).as_bytes())
+        .await?;
+    let mut reader = BufReader::new(read_half);
+    let mut status = String::new();
+    reader.read_line(&mut status).await?;
+    let mut body = String::new();
+    reader.read_to_string(&mut body).await?;
+    let status = status.trim_end();
+    if status == "ok" {
+        Ok(Ok(body))
+    } else {
+        Ok(Err(status
+            .strip_prefix("error\t")
+            .unwrap_or(status)
+            .to_string()))
+    }
+}
@@ -0,0 +1,16 @@
+// Shared with the package tool, which includes this file.
+// Arguments are the application name, icon name and command line.
+macro_rules! DESKTOP_ENTRY_FMT {
+    () => {
+        "[Desktop Entry]
+Type=Application
+Version=1.5
+Name={}
+Icon={}
+Categories=Utility
+Exec={}
+Terminal=false
+X-GNOME-UsesNotifications=true
+"
+    };
+}
@@ -0,0 +1,4 @@
+// Shared with the package tool, which includes this file for its systemd unit.
+// What the app exits with when another instance is already running, which isn't
+// a failure to restart from (EX_TEMPFAIL).
+pub const ALREADY_RUNNING: i32 = 75;
@@ -0,0 +1,92 @@
+// Devices that have sent notifications before, remembered so that --scan can
+// connect to them again. One peripheral ID per line. Devices paired with the
+// computer are tried too, where the OS says which they are.
+
+#[cfg(all(unix, not(target_os = "macos")))]
+use crate::bluez;
+use std::collections::HashSet;
+use std::io;
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn data_dir() -> std::path::PathBuf {
+    match std::env::var_os("XDG_DATA_HOME") {
+        Some(data_dir) => std::path::PathBuf::from(data_dir),
+        None => std::path::Path::new(&std::env::var_os("HOME").unwrap_or_default())
+            .join(".local")
+            .join("share"),
+    }
+}
+#[cfg(target_os = "macos")]
+fn data_dir() -> std::path::PathBuf {
+    std::path::Path::new(&std::env::var_os("HOME").unwrap_or_default())
+        .join("Library")
+        .join("Application Support")
+}
+#[cfg(windows)]
+fn data_dir() -> std::path::PathBuf {
+    std::path::PathBuf::from(std::env::var_os("APPDATA").unwrap_or_default())
+}
+
+fn known_devices_path() -> std::path::PathBuf {
+    data_dir().join(env!("CARGO_PKG_NAME")).join("devices")
+}
+
+pub fn load() -> HashSet<String> {
+    match std::fs::read_to_string(known_devices_path()) {
+        Ok(contents) => contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                eprintln!("error reading known devices: {e:?}");
+            }
+            HashSet::new()
+        }
+    }
+}
+
+pub fn save(devices: &HashSet<String>) -> io::Result<()> {
+    let path = known_devices_path();
+    let mut lines: Vec<&str> = devices.iter().map(String::as_str).collect();
+    lines.sort_unstable();
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(
+        path,
+        lines
+            .iter()
+            .map(|line| format!("{line}\n"))
+            .collect::<String>(),
+    )
+}
+
+// BlueZ object paths of paired devices, without the /org/bluez/, which is what
+// their peripheral IDs are
+#[cfg(all(unix, not(target_os = "macos")))]
+pub async fn paired(system: &zbus::Connection) -> HashSet<String> {
+    let objects = match bluez::managed_objects(system, bluez::BLUEZ).await {
+        Ok(objects) => objects,
+        Err(e) => {
+            eprintln!("error listing paired devices: {e:?}");
+            return HashSet::new();
+        }
+    };
+    objects
+        .into_iter()
+        .filter(|(_, interfaces)| {
+            interfaces.iter().any(|(interface, properties)| {
+                interface.as_str() == "org.bluez.Device1"
+                    && properties
+                        .get("Paired")
+                        .is_some_and(|paired| paired.downcast_ref::<bool>() == Ok(true))
+            })
+        })
+        .filter_map(|(path, _)| {
+            path.as_str()
+                .strip_prefix(bluez::PATH_PREFIX)
+                .map(str::to_string)
+        })
+        .collect()
+}
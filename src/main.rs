@@ -1,5 +1,26 @@
 #![windows_subsystem = "windows"]
 
+#[cfg(all(unix, not(target_os = "macos")))]
+#[macro_use]
+mod desktop_entry;
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod actions;
+#[cfg(all(unix, not(target_os = "macos")))]
+mod advertising;
+#[cfg(windows)]
+mod app_id;
+mod autostart;
+#[cfg(all(unix, not(target_os = "macos")))]
+mod bluez;
+mod control;
+mod exit_status;
+mod known_devices;
+#[cfg(all(unix, not(target_os = "macos")))]
+mod pairing;
+
+#[cfg(all(unix, not(target_os = "macos")))]
+use actions::{DesktopActions, DesktopEvent};
 use ancs::attributes::action::ActionID;
 use ancs::attributes::app::AppAttributeID;
 use ancs::attributes::category::CategoryID;
@@ -11,9 +32,12 @@ use ancs::characteristics::control_point::*;
 use ancs::characteristics::data_source::*;
 use ancs::characteristics::notification_source::Notification as GattNotification;
 use btleplug::api::{
-    Central, CentralEvent, Characteristic, Manager as _, Peripheral as _, WriteType,
+    Central, CentralEvent, CentralState, Characteristic, Manager as _, Peripheral as _, ScanFilter,
+    WriteType,
 };
-use btleplug::platform::{Manager, Peripheral};
+use btleplug::platform::{Adapter, Manager, Peripheral, PeripheralId};
+use clap::{command, value_parser, ArgMatches};
+use control::{ControlRequest, Pause};
 use futures::stream::StreamExt;
 #[cfg(not(windows))]
 use notify_rust::NotificationHandle;
@@ -23,8 +47,8 @@ use std::error::Error;
 use std::time::{Duration, Instant};
 use tao::event::{Event, WindowEvent};
 use tao::event_loop::{ControlFlow, EventLoop};
-use tokio::sync::{oneshot, watch};
-use tray_icon::menu::{Menu, MenuEvent, MenuItem, PredefinedMenuItem};
+use tokio::sync::{mpsc, oneshot, watch};
+use tray_icon::menu::{CheckMenuItem, Menu, MenuEvent, MenuItem, PredefinedMenuItem};
 use tray_icon::TrayIconBuilder;
 
 // Windows does not have notification handles
@@ -41,6 +65,113 @@ struct AppGlobals {
     ns_char: Characteristic,
     cp_char: Option<Characteristic>,
     ds_char: Option<Characteristic>,
+    pause_rx: watch::Receiver<Pause>,
+    #[cfg(all(unix, not(target_os = "macos")))]
+    options: Options,
+    // from --label, to tell devices' notifications apart
+    label: Option<String>,
+    stats: SessionStats,
+    #[cfg(all(unix, not(target_os = "macos")))]
+    desktop_actions: DesktopActions,
+}
+
+#[derive(Clone)]
+struct Options {
+    // perform the negative action on the device when a notification is dismissed here
+    #[cfg(all(unix, not(target_os = "macos")))]
+    dismiss_on_close: bool,
+    // look for known devices and connect to them, rather than waiting for them to connect
+    scan: bool,
+    // ask devices to connect to us, rather than waiting for them to
+    advertise: bool,
+    // use only the adapter with this name, rather than all of them
+    adapter: Option<String>,
+    // devices to forward notifications from, by address or name, or all of them if empty
+    allow: Vec<String>,
+    // devices never to forward notifications from
+    deny: Vec<String>,
+    // friendly names for devices, by address or name
+    labels: Vec<(String, String)>,
+}
+
+fn matches_device(patterns: &[String], names: &[String]) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| names.iter().any(|name| name.eq_ignore_ascii_case(pattern)))
+}
+
+impl Options {
+    fn allows(&self, names: &[String]) -> bool {
+        !matches_device(&self.deny, names)
+            && (self.allow.is_empty() || matches_device(&self.allow, names))
+    }
+
+    fn label(&self, names: &[String]) -> Option<String> {
+        self.labels
+            .iter()
+            .find(|(device, _)| matches_device(std::slice::from_ref(device), names))
+            .map(|(_, label)| label.clone())
+    }
+}
+
+// what --allow, --deny and --label can refer to a device by
+async fn device_names(peripheral: &Peripheral) -> Vec<String> {
+    let mut names = vec![
+        peripheral.address().to_string(),
+        peripheral.id().to_string(),
+    ];
+    if let Ok(Some(properties)) = peripheral.properties().await {
+        names.extend(properties.local_name);
+    }
+    names
+}
+
+// for diagnosing slow or cut-off notifications
+#[derive(Clone, Copy, Default)]
+struct SessionStats {
+    // None if the platform doesn't say
+    mtu: Option<u16>,
+    // notifications and indications from the device, and their payload sizes
+    packets: u64,
+    bytes: u64,
+}
+
+// requests from the control channel, routed to each device's task
+enum SessionCommand {
+    List(oneshot::Sender<String>),
+    Stats(oneshot::Sender<SessionStats>),
+    // replies None if the notification isn't from this device
+    PerformAction(u32, ActionID, oneshot::Sender<Option<Result<(), String>>>),
+    // from the action dispatcher, for a desktop notification this device sent
+    #[cfg(all(unix, not(target_os = "macos")))]
+    DesktopEvent(DesktopEvent),
+}
+
+// what a device's task gets from the rest of the app
+struct SessionContext {
+    quit_rx: watch::Receiver<()>,
+    disconnect_rx: oneshot::Receiver<()>,
+    command_rx: mpsc::Receiver<SessionCommand>,
+    pause_rx: watch::Receiver<Pause>,
+    #[cfg(all(unix, not(target_os = "macos")))]
+    options: Options,
+    label: Option<String>,
+    // for what btleplug doesn't say, if there's a system bus
+    #[cfg(all(unix, not(target_os = "macos")))]
+    system: Option<zbus::Connection>,
+    #[cfg(all(unix, not(target_os = "macos")))]
+    desktop_actions: DesktopActions,
+}
+
+struct DeviceSession {
+    address: String,
+    // the --label for it, or else its address
+    label: String,
+    connected_at: Instant,
+    // the name of the adapter it's connected through
+    adapter: String,
+    disconnect_tx: oneshot::Sender<()>,
+    command_tx: mpsc::Sender<SessionCommand>,
 }
 
 async fn write_details_request(
@@ -77,13 +208,31 @@ async fn write_appinfo_request(
     Ok(())
 }
 
-// only Windows can set the app ID per notification
+async fn write_action_request(
+    app: &mut AppGlobals,
+    notification_uid: u32,
+    action_id: ActionID,
+) -> Result<(), btleplug::Error> {
+    let req = PerformNotificationActionRequest {
+        command_id: CommandID::PerformNotificationAction,
+        notification_uid,
+        action_id,
+    };
+    let out: Vec<u8> = req.into();
+    app.peripheral
+        .write(app.cp_char.as_ref().unwrap(), &out, WriteType::WithResponse)
+        .await?;
+    Ok(())
+}
+
+// Windows only shows notifications from app IDs it knows, so they come from
+// the installed shortcut rather than the app on the device
 #[cfg(windows)]
-fn set_app_id(send: &mut Notification, appid: &str) {
-    send.app_id(appid);
+fn set_app_id(send: &mut Notification) {
+    send.app_id(app_id::APP_USER_MODEL_ID);
 }
 #[cfg(not(windows))]
-fn set_app_id(_send: &mut Notification, _appid: &str) {}
+fn set_app_id(_send: &mut Notification) {}
 
 fn action_id_for_notif(recv: Option<&GattNotification>, action: ActionID) -> &'static str {
     if recv.is_some() && recv.unwrap().category_id == CategoryID::IncomingCall {
@@ -115,7 +264,6 @@ async fn update_notif_with_notif_attributes(
         match attr.id {
             NotificationAttributeID::AppIdentifier => {
                 if let Some(appid) = &attr.value {
-                    set_app_id(send, appid);
                     if cfg!(all(unix, not(target_os = "macos"))) {
                         // only XDG will use the application name
                         if let Some(appname) = app.app_names.get(appid) {
@@ -134,7 +282,10 @@ async fn update_notif_with_notif_attributes(
             }
             NotificationAttributeID::Title => {
                 if let Some(title) = &attr.value {
-                    send.summary(title);
+                    match &app.label {
+                        Some(label) => send.summary(&format!("[{label}] {title}")),
+                        None => send.summary(title),
+                    };
                 }
             }
             NotificationAttributeID::Subtitle => {
@@ -199,54 +350,63 @@ fn update_handle(_handle: &mut NotificationHandle) {}
 
 // only XDG can handle actions
 #[cfg(all(unix, not(target_os = "macos")))]
-fn add_action_handlers(app: &AppGlobals, notif_id: u32, notification_uid: u32) {
-    let received_notif = app.received_notifs.get(&notification_uid);
-    let pos_action_id = action_id_for_notif(received_notif, ActionID::Positive);
-    let neg_action_id = action_id_for_notif(received_notif, ActionID::Negative);
-    let peripheral = app.peripheral.clone();
-    let cp_char = app.cp_char.clone();
-
-    if received_notif
-        .unwrap()
-        .event_flags
-        .contains(EventFlag::PositiveAction)
-        || received_notif
-            .unwrap()
-            .event_flags
-            .contains(EventFlag::NegativeAction)
+fn add_action_handlers(app: &mut AppGlobals, notif_id: u32, notification_uid: u32) {
+    let event_flags = &app.received_notifs[&notification_uid].event_flags;
+    if app.cp_char.is_some()
+        && (event_flags.contains(EventFlag::PositiveAction)
+            || event_flags.contains(EventFlag::NegativeAction))
     {
-        let rt = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        std::thread::spawn(move || {
-            notify_rust::handle_action(notif_id, |result| {
-                if let notify_rust::ActionResponse::Custom(action) = result {
-                    if action == &pos_action_id || action == &neg_action_id {
-                        let req = PerformNotificationActionRequest {
-                            command_id: CommandID::PerformNotificationAction,
-                            notification_uid,
-                            action_id: if action == &pos_action_id {
-                                ActionID::Positive
-                            } else {
-                                ActionID::Negative
-                            },
-                        };
-                        let out: Vec<u8> = req.into();
-                        rt.block_on(peripheral.write(
-                            cp_char.as_ref().unwrap(),
-                            &out,
-                            WriteType::WithResponse,
-                        ))
-                        .unwrap();
-                    }
-                }
-            });
-        });
+        app.desktop_actions.register(notif_id, notification_uid);
     }
 }
 #[cfg(not(all(unix, not(target_os = "macos"))))]
-fn add_action_handlers(_app: &AppGlobals, _notif_id: u32, _notification_uid: u32) {}
+fn add_action_handlers(_app: &mut AppGlobals, _notif_id: u32, _notification_uid: u32) {}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn forget_action_handlers(app: &mut AppGlobals, notification_uid: u32) {
+    app.desktop_actions.forget(notification_uid);
+}
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn forget_action_handlers(_app: &mut AppGlobals, _notification_uid: u32) {}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+async fn handle_desktop_event(app: &mut AppGlobals, event: DesktopEvent) {
+    let (notification_uid, action_id) = match event {
+        DesktopEvent::Action(notif_id, action) => {
+            let Some(notification_uid) = app.desktop_actions.closed(notif_id) else {
+                return;
+            };
+            let received_notif = app.received_notifs.get(&notification_uid);
+            if action == action_id_for_notif(received_notif, ActionID::Positive) {
+                (notification_uid, ActionID::Positive)
+            } else if action == action_id_for_notif(received_notif, ActionID::Negative) {
+                (notification_uid, ActionID::Negative)
+            } else {
+                return;
+            }
+        }
+        DesktopEvent::Closed(notif_id, reason) => {
+            let Some(notification_uid) = app.desktop_actions.closed(notif_id) else {
+                return;
+            };
+            // keep the device in sync when the user closes the notification
+            let has_negative_action = app
+                .received_notifs
+                .get(&notification_uid)
+                .is_some_and(|recv| recv.event_flags.contains(EventFlag::NegativeAction));
+            if !(app.options.dismiss_on_close
+                && has_negative_action
+                && matches!(reason, notify_rust::CloseReason::Dismissed))
+            {
+                return;
+            }
+            (notification_uid, ActionID::Negative)
+        }
+    };
+    if let Err(e) = write_action_request(app, notification_uid, action_id).await {
+        eprintln!("error performing action on notification {notification_uid}: {e:?}");
+    }
+}
 
 // only XDG can get a handle's ID
 #[cfg(all(unix, not(target_os = "macos")))]
@@ -285,7 +445,10 @@ async fn handle_ds(app: &mut AppGlobals, value: Vec<u8>) -> Result<(), btleplug:
                         update_handle(handle);
                     }
                     if let Some(send) = app.pending_notifs.remove(&recv.notification_uid) {
-                        if let Ok(handle) = show_notification(&send) {
+                        if app.pause_rx.borrow().is_paused() {
+                            // keep it listed for the command line, just don't show it
+                            app.pending_notifs.insert(recv.notification_uid, send);
+                        } else if let Ok(handle) = show_notification(&send) {
                             let notif_id = get_handle_id(&handle);
                             let notification_uid = recv.notification_uid;
                             app.sent_notifs.insert(notification_uid, handle);
@@ -367,6 +530,23 @@ fn has_capability(_cap: &str) -> bool {
     false
 }
 
+fn category_hint(category_id: &CategoryID) -> Option<&'static str> {
+    match category_id {
+        CategoryID::IncomingCall => Some("x-apple.call.incoming"),
+        CategoryID::MissedCall => Some("x-apple.call.missed"),
+        CategoryID::Voicemail => Some("x-apple.voicemail"),
+        CategoryID::Social => Some("x-apple.social"),
+        CategoryID::Schedule => Some("x-apple.schedule"),
+        CategoryID::Email => Some("email"),
+        CategoryID::News => Some("x-apple.news"),
+        CategoryID::HealthAndFitness => Some("x-apple.health-and-fitness"),
+        CategoryID::BusinessAndFinance => Some("x-apple.business-and-finance"),
+        CategoryID::Location => Some("x-apple.location"),
+        CategoryID::Entertainment => Some("x-apple.entertainment"),
+        CategoryID::Other => None,
+    }
+}
+
 async fn set_notif_from_gatt(
     app: &mut AppGlobals,
     notification_uid: u32,
@@ -386,27 +566,8 @@ async fn set_notif_from_gatt(
         set_urgency(send, Urgency::Critical);
         send.timeout(Timeout::Never);
     }
-    if recv.category_id != CategoryID::Other {
-        add_hint(
-            send,
-            Hint::Category(
-                match recv.category_id {
-                    CategoryID::IncomingCall => "x-apple.call.incoming",
-                    CategoryID::MissedCall => "x-apple.call.missed",
-                    CategoryID::Voicemail => "x-apple.voicemail",
-                    CategoryID::Social => "x-apple.social",
-                    CategoryID::Schedule => "x-apple.schedule",
-                    CategoryID::Email => "email",
-                    CategoryID::News => "x-apple.news",
-                    CategoryID::HealthAndFitness => "x-apple.health-and-fitness",
-                    CategoryID::BusinessAndFinance => "x-apple.business-and-finance",
-                    CategoryID::Location => "x-apple.location",
-                    CategoryID::Entertainment => "x-apple.entertainment",
-                    CategoryID::Other => unreachable!(),
-                }
-                .to_string(),
-            ),
-        );
+    if let Some(category) = category_hint(&recv.category_id) {
+        add_hint(send, Hint::Category(category.to_string()));
         // what to do here... XDG is the only one that supports icons at the moment,
         // so we can assume freedesktop icons work. but not all of these are standard.
         // XDG also supports icons in file:///, and so should Windows too eventually
@@ -433,8 +594,7 @@ async fn set_notif_from_gatt(
     // macOS should get the default app icon from the bundle, XDG should get it from the desktop file
     if app.cp_char.is_some() {
         let mut attrs = vec![(NotificationAttributeID::Title, Some(u16::MAX))];
-        if cfg!(any(windows, all(unix, not(target_os = "macos")))) {
-            // only Windows can set the app ID per notification
+        if cfg!(all(unix, not(target_os = "macos"))) {
             // only XDG will use the application name
             attrs.push((NotificationAttributeID::AppIdentifier, None));
         }
@@ -473,6 +633,11 @@ async fn handle_ns(app: &mut AppGlobals, value: Vec<u8>) -> Result<(), btleplug:
         match recv.event_id {
             EventID::NotificationAdded => {
                 let mut send = Notification::new();
+                set_app_id(&mut send);
+                if let Some(label) = &app.label {
+                    // in case there's no title to go with it
+                    send.summary(&format!("[{label}]"));
+                }
                 add_hint(&mut send, Hint::ActionIcons(true));
                 add_hint(&mut send, Hint::DesktopEntry(env!("CARGO_PKG_NAME").into()));
                 let notification_uid = recv.notification_uid;
@@ -495,6 +660,7 @@ async fn handle_ns(app: &mut AppGlobals, value: Vec<u8>) -> Result<(), btleplug:
                 if let Some(handle) = app.sent_notifs.remove(&recv.notification_uid) {
                     close_handle(handle);
                 }
+                forget_action_handlers(app, recv.notification_uid);
                 app.pending_notifs.remove(&recv.notification_uid);
                 app.received_notifs.remove(&recv.notification_uid);
             }
@@ -505,11 +671,101 @@ async fn handle_ns(app: &mut AppGlobals, value: Vec<u8>) -> Result<(), btleplug:
     Ok(())
 }
 
+// keep each record on one line for the control channel
+fn control_field(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+fn list_notifications(app: &AppGlobals) -> String {
+    let address = app.peripheral.address();
+    let mut uids: Vec<&u32> = app.received_notifs.keys().collect();
+    uids.sort();
+    let mut out = String::new();
+    for notification_uid in uids {
+        let recv = &app.received_notifs[notification_uid];
+        let send = match app.pending_notifs.get(notification_uid) {
+            Some(send) => Some(send),
+            None => app.sent_notifs.get(notification_uid).map(|handle| {
+                let send: &Notification = handle;
+                send
+            }),
+        };
+        let (appname, summary, body) = send
+            .map(|send| {
+                (
+                    send.appname.as_str(),
+                    send.summary.as_str(),
+                    send.body.as_str(),
+                )
+            })
+            .unwrap_or_default();
+        out += &format!(
+            "{address}\t{notification_uid}\t{}\t{}\t{}\t{}\n",
+            category_hint(&recv.category_id).unwrap_or("other"),
+            control_field(appname),
+            control_field(summary),
+            control_field(body),
+        );
+    }
+    out
+}
+
+async fn perform_action(
+    app: &mut AppGlobals,
+    notification_uid: u32,
+    action_id: ActionID,
+) -> Option<Result<(), String>> {
+    let recv = app.received_notifs.get(&notification_uid)?;
+    let flag = match action_id {
+        ActionID::Positive => EventFlag::PositiveAction,
+        ActionID::Negative => EventFlag::NegativeAction,
+    };
+    if !recv.event_flags.contains(flag) || app.cp_char.is_none() {
+        return Some(Err(format!(
+            "notification {notification_uid} doesn't have that action"
+        )));
+    }
+    Some(
+        write_action_request(app, notification_uid, action_id)
+            .await
+            .map_err(|e| e.to_string()),
+    )
+}
+
+// btleplug doesn't say what MTU a connection uses, but BlueZ reports it on
+// each characteristic
+#[cfg(all(unix, not(target_os = "macos")))]
+async fn reported_mtu(system: &zbus::Connection, peripheral: &Peripheral) -> Option<u16> {
+    let device_path = format!("{}{}/", bluez::PATH_PREFIX, peripheral.id());
+    let uuid = ancs::characteristics::notification_source::NOTIFICATION_SOURCE_UUID;
+    let objects = bluez::managed_objects(system, bluez::BLUEZ).await.ok()?;
+    objects
+        .iter()
+        .filter(|(path, _)| path.as_str().starts_with(&device_path))
+        .filter_map(|(_, interfaces)| {
+            interfaces
+                .iter()
+                .find(|(interface, _)| interface.as_str() == "org.bluez.GattCharacteristic1")
+                .map(|(_, properties)| properties)
+        })
+        .find(|properties| {
+            properties
+                .get("UUID")
+                .and_then(|value| value.downcast_ref::<String>().ok())
+                .and_then(|value| value.parse::<uuid::Uuid>().ok())
+                == Some(uuid)
+        })
+        .and_then(|properties| properties.get("MTU")?.downcast_ref::<u16>().ok())
+}
+
 async fn watch_device(
     peripheral: Peripheral,
-    mut quit_rx: watch::Receiver<()>,
-    mut disconnect_rx: oneshot::Receiver<()>,
+    context: SessionContext,
 ) -> Result<(), btleplug::Error> {
+    let mut quit_rx = context.quit_rx;
+    let mut disconnect_rx = context.disconnect_rx;
+    let mut command_rx = context.command_rx;
+
     // find the characteristics we want
     let chars = peripheral.characteristics();
     // Support for the Notification Source characteristic is mandatory
@@ -524,7 +780,11 @@ async fn watch_device(
         .iter()
         .find(|c| c.uuid == ancs::characteristics::data_source::DATA_SOURCE_UUID);
 
-    println!("subscribing to {ns_char:?} on {}", peripheral.address());
+    let label = context
+        .label
+        .clone()
+        .unwrap_or_else(|| peripheral.address().to_string());
+    println!("subscribing to {ns_char:?} on {label}");
     peripheral.subscribe(ns_char).await?;
     if let Some(ds_char_ok) = ds_char {
         println!("subscribing to {ds_char_ok:?}");
@@ -533,6 +793,20 @@ async fn watch_device(
 
     let mut notification_stream = peripheral.notifications().await?;
 
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let mtu = match &context.system {
+        Some(system) => reported_mtu(system, &peripheral).await,
+        None => None,
+    };
+    // only BlueZ says
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    let mtu = None;
+    match mtu {
+        Some(mtu) => println!("MTU is {mtu} on {label}"),
+        None => println!("MTU is unknown on {label}"),
+    }
+    let connected_at = Instant::now();
+
     let mut app = AppGlobals {
         peripheral: peripheral.clone(),
         received_notifs: HashMap::new(),
@@ -543,6 +817,16 @@ async fn watch_device(
         ns_char: ns_char.clone(),
         cp_char: cp_char.cloned(),
         ds_char: ds_char.cloned(),
+        pause_rx: context.pause_rx,
+        #[cfg(all(unix, not(target_os = "macos")))]
+        options: context.options,
+        label: context.label,
+        stats: SessionStats {
+            mtu,
+            ..Default::default()
+        },
+        #[cfg(all(unix, not(target_os = "macos")))]
+        desktop_actions: context.desktop_actions,
     };
 
     // Process while the BLE connection is not broken or stopped.
@@ -554,7 +838,26 @@ async fn watch_device(
             _ = &mut disconnect_rx => {
                 break;
             },
+            Some(command) = command_rx.recv() => {
+                match command {
+                    SessionCommand::List(reply) => {
+                        let _ = reply.send(list_notifications(&app));
+                    }
+                    SessionCommand::Stats(reply) => {
+                        let _ = reply.send(app.stats);
+                    }
+                    SessionCommand::PerformAction(notification_uid, action_id, reply) => {
+                        let _ = reply.send(perform_action(&mut app, notification_uid, action_id).await);
+                    }
+                    #[cfg(all(unix, not(target_os = "macos")))]
+                    SessionCommand::DesktopEvent(event) => {
+                        handle_desktop_event(&mut app, event).await;
+                    }
+                }
+            },
             Some(data) = notification_stream.next() => {
+                app.stats.packets += 1;
+                app.stats.bytes += data.value.len() as u64;
                 if data.uuid == ancs::characteristics::notification_source::NOTIFICATION_SOURCE_UUID {
                     handle_ns(&mut app, data.value).await?;
                 } else if data.uuid == ancs::characteristics::data_source::DATA_SOURCE_UUID {
@@ -577,6 +880,12 @@ async fn watch_device(
     if let Err(e) = app.peripheral.unsubscribe(&app.ns_char).await {
         eprintln!("error unsubscribing from NS: {e:?}");
     }
+    println!(
+        "received {} packets, {} bytes from {label} in {}s",
+        app.stats.packets,
+        app.stats.bytes,
+        connected_at.elapsed().as_secs()
+    );
     Ok(())
 }
 
@@ -596,11 +905,259 @@ fn load_icon() -> tray_icon::Icon {
     tray_icon::Icon::from_rgba(icon_rgba, icon_width, icon_height).unwrap()
 }
 
+fn parse_label(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((device, label)) if !device.is_empty() && !label.is_empty() => {
+            Ok((device.to_string(), label.to_string()))
+        }
+        _ => Err(format!("expected DEVICE=LABEL, got {value:?}")),
+    }
+}
+
+fn cli() -> clap::Command {
+    let uid_arg = clap::Arg::new("uid")
+        .value_name("UID")
+        .required(true)
+        .value_parser(value_parser!(u32));
+    let command = command!()
+        .subcommand(clap::Command::new("list").about("List notifications from connected devices"))
+        .subcommand(
+            clap::Command::new("dismiss")
+                .about("Perform a notification's negative action")
+                .arg(uid_arg.clone()),
+        )
+        .subcommand(
+            clap::Command::new("accept")
+                .about("Perform a notification's positive action")
+                .arg(uid_arg),
+        )
+        .subcommand(
+            clap::Command::new("pause")
+                .about("Stop showing notifications, until resumed or for a while")
+                .arg(
+                    clap::Arg::new("for")
+                        .long("for")
+                        .value_name("DURATION")
+                        .required(false)
+                        .value_parser(control::parse_duration),
+                ),
+        )
+        .subcommand(clap::Command::new("resume").about("Show notifications again"))
+        .subcommand(clap::Command::new("status").about("Show the state of the running instance"))
+        .arg(
+            clap::Arg::new("scan")
+                .long("scan")
+                .help(
+                    "Scan for devices and connect to them, instead of waiting for them to connect",
+                )
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("advertise")
+                .long("advertise")
+                .help("Advertise for devices to offer notifications, so they connect by themselves")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("adapter")
+                .long("adapter")
+                .value_name("NAME")
+                .help("Only use this Bluetooth adapter, e.g. hci0, instead of all of them"),
+        )
+        .arg(
+            clap::Arg::new("allow")
+                .long("allow")
+                .value_name("DEVICE")
+                .help("Only forward notifications from this device, by address or name")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            clap::Arg::new("deny")
+                .long("deny")
+                .value_name("DEVICE")
+                .help("Never forward notifications from this device, by address or name")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            clap::Arg::new("label")
+                .long("label")
+                .value_name("DEVICE=LABEL")
+                .help("Show this device's notifications and status under another name")
+                .value_parser(parse_label)
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            clap::Arg::new("headless")
+                .long("headless")
+                .help("Run without a tray icon, e.g. as a service")
+                .action(clap::ArgAction::SetTrue),
+        );
+    // only the freedesktop notification server tells us when a notification is dismissed
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let command = command.arg(
+        clap::Arg::new("dismiss-on-close")
+            .long("dismiss-on-close")
+            .help("Clear notifications on the device when they're dismissed on the desktop")
+            .action(clap::ArgAction::SetTrue),
+    );
+    command
+}
+
+// the well-known name for D-Bus activation, as in the package's service file
+#[cfg(all(unix, not(target_os = "macos")))]
+const BUS_NAME: &str = concat!("net.boatcake.", env!("CARGO_PKG_NAME"));
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    let mut terminate =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+}
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// no tray icon or event loop, just the devices, until told to stop
+fn run_headless(
+    rt: tokio::runtime::Runtime,
+    options: Options,
+    control_listener: Option<control::ControlListener>,
+) -> i32 {
+    let (quit_tx, quit_rx) = watch::channel(());
+    // nothing shows these, besides the log
+    let (adapter_tx, _) = watch::channel(true);
+    let (devices_tx, _) = watch::channel(Vec::new());
+    rt.spawn(async move {
+        shutdown_signal().await;
+        let _ = quit_tx.send(());
+    });
+    rt.block_on(async move {
+        // held until exit, so whatever activated the app sees it running
+        #[cfg(all(unix, not(target_os = "macos")))]
+        let _bus =
+            match zbus::connection::Builder::session().and_then(|builder| builder.name(BUS_NAME)) {
+                Ok(builder) => builder
+                    .build()
+                    .await
+                    .map_err(|e| eprintln!("couldn't request bus name {BUS_NAME}: {e:?}"))
+                    .ok(),
+                Err(e) => {
+                    eprintln!("couldn't request bus name {BUS_NAME}: {e:?}");
+                    None
+                }
+            };
+        match inner_main(quit_rx, adapter_tx, devices_tx, options, control_listener).await {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("{e}");
+                1
+            }
+        }
+    })
+}
+
+fn run_control_command(name: &str, matches: &ArgMatches) -> i32 {
+    let request = match name {
+        "list" => ControlRequest::List,
+        "dismiss" => ControlRequest::Dismiss(*matches.get_one::<u32>("uid").unwrap()),
+        "accept" => ControlRequest::Accept(*matches.get_one::<u32>("uid").unwrap()),
+        "pause" => ControlRequest::Pause(matches.get_one::<Duration>("for").copied()),
+        "resume" => ControlRequest::Resume,
+        "status" => ControlRequest::Status,
+        _ => unreachable!(),
+    };
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    match rt.block_on(control::send(request)) {
+        Ok(Ok(body)) => {
+            print!("{body}");
+            0
+        }
+        Ok(Err(e)) => {
+            eprintln!("{e}");
+            1
+        }
+        Err(e) => {
+            eprintln!("couldn't reach the running instance: {e}");
+            2
+        }
+    }
+}
+
+// The app is built without a console of its own on Windows, so output from
+// subcommands, --help and the like would go nowhere without the one it was
+// started from. Nothing happens if it wasn't started from one.
+#[cfg(windows)]
+fn attach_console() {
+    use windows_sys::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+#[cfg(not(windows))]
+fn attach_console() {}
+
 fn main() {
+    attach_console();
+    let matches = cli().get_matches();
+    if let Some((name, sub_matches)) = matches.subcommand() {
+        std::process::exit(run_control_command(name, sub_matches));
+    }
+    let options = Options {
+        #[cfg(all(unix, not(target_os = "macos")))]
+        dismiss_on_close: matches.get_flag("dismiss-on-close"),
+        scan: matches.get_flag("scan"),
+        advertise: matches.get_flag("advertise"),
+        adapter: matches.get_one::<String>("adapter").cloned(),
+        allow: matches
+            .get_many::<String>("allow")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        deny: matches
+            .get_many::<String>("deny")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        labels: matches
+            .get_many::<(String, String)>("label")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+    };
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    // two instances would both subscribe to the same devices and show everything twice
+    let control_listener = match rt.block_on(control::bind()) {
+        Ok(listener) => Some(listener),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            eprintln!(
+                "{} is already running, use its subcommands to control it",
+                env!("CARGO_PKG_NAME")
+            );
+            std::process::exit(exit_status::ALREADY_RUNNING);
+        }
+        Err(e) => {
+            eprintln!("couldn't open the control channel: {e:?}");
+            None
+        }
+    };
+
+    if matches.get_flag("headless") {
+        std::process::exit(run_headless(rt, options, control_listener));
+    }
+
     let event_loop = EventLoop::new();
 
+    let autostart_item = CheckMenuItem::new("Start at login", true, autostart::is_enabled(), None);
+    let autostart_id = autostart_item.id().clone();
     let quit_item = MenuItem::new("Quit", true, None);
     let quit_id = quit_item.id().clone();
+    // only in the menu while there's no adapter
+    let unavailable_item = MenuItem::new("Bluetooth adapter unavailable", false, None);
     let tray_menu = Menu::with_items(&[
         &MenuItem::new(
             concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")),
@@ -609,11 +1166,30 @@ fn main() {
         ),
         &PredefinedMenuItem::separator(),
         &quit_item,
-    ]).unwrap();
+    ])
+    .unwrap();
+    if autostart::is_available() {
+        tray_menu.insert(&autostart_item, 2).unwrap();
+    }
+    // pairing goes through BlueZ
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let pair_item = MenuItem::new("Pair new device…", true, None);
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        tray_menu.insert(&pair_item, 2).unwrap();
+    }
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let pair_id = pair_item.id().clone();
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let pair_adapter = options.adapter.clone();
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let mut pairing_task: Option<tokio::task::JoinHandle<()>> = None;
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let rt_handle = rt.handle().clone();
     let icon_tray = load_icon();
     let mut tray_icon = Some(
         TrayIconBuilder::new()
-            .with_menu(Box::new(tray_menu))
+            .with_menu(Box::new(tray_menu.clone()))
             .with_tooltip(env!("CARGO_BIN_NAME"))
             .with_icon(icon_tray)
             .build()
@@ -622,9 +1198,17 @@ fn main() {
     tray_icon.as_mut().unwrap().set_icon_as_template(true);
 
     let (quit_tx, quit_rx) = watch::channel(());
-    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (adapter_tx, mut adapter_rx) = watch::channel(true);
+    let (devices_tx, mut devices_rx) = watch::channel(Vec::new());
     let mut join_handle = Some(std::thread::spawn(move || {
-        rt.block_on(inner_main(quit_rx.clone())).unwrap()
+        rt.block_on(inner_main(
+            quit_rx.clone(),
+            adapter_tx,
+            devices_tx,
+            options,
+            control_listener,
+        ))
+        .unwrap()
     }));
 
     let menu_channel = MenuEvent::receiver();
@@ -635,7 +1219,54 @@ fn main() {
             tray_icon.take();
             *control_flow = ControlFlow::Exit;
         }
+        #[cfg(all(unix, not(target_os = "macos")))]
+        if pairing_task.as_ref().is_some_and(|task| task.is_finished()) {
+            pairing_task = None;
+            pair_item.set_enabled(true);
+        }
+        let adapter_changed = adapter_rx.has_changed().unwrap_or(false);
+        let devices_changed = devices_rx.has_changed().unwrap_or(false);
+        if adapter_changed || devices_changed {
+            let available = *adapter_rx.borrow_and_update();
+            let devices = devices_rx.borrow_and_update().join(", ");
+            if let Some(tray_icon) = &tray_icon {
+                if adapter_changed {
+                    let result = if available {
+                        tray_menu.remove(&unavailable_item)
+                    } else {
+                        tray_menu.insert(&unavailable_item, 1)
+                    };
+                    if let Err(e) = result {
+                        eprintln!("error updating menu: {e:?}");
+                    }
+                }
+                let tooltip = if !available {
+                    format!("{} (Bluetooth adapter unavailable)", env!("CARGO_BIN_NAME"))
+                } else if !devices.is_empty() {
+                    format!("{} ({devices})", env!("CARGO_BIN_NAME"))
+                } else {
+                    env!("CARGO_BIN_NAME").to_string()
+                };
+                if let Err(e) = tray_icon.set_tooltip(Some(tooltip)) {
+                    eprintln!("error updating tooltip: {e:?}");
+                }
+            }
+        }
         if let Ok(menu_event) = menu_channel.try_recv() {
+            #[cfg(all(unix, not(target_os = "macos")))]
+            if menu_event.id == pair_id && pairing_task.is_none() {
+                pair_item.set_enabled(false);
+                pairing_task =
+                    Some(rt_handle.spawn(pairing::pair_new_device(pair_adapter.clone())));
+            }
+            if menu_event.id == autostart_id {
+                // the item has already toggled itself
+                let enabled = autostart_item.is_checked();
+                if let Err(e) = autostart::set_enabled(enabled) {
+                    eprintln!("error changing start at login: {e:?}");
+                    autostart_item.set_checked(!enabled);
+                }
+            }
             if menu_event.id == quit_id {
                 quit_tx.send(()).unwrap();
                 join_handle.take().unwrap().join().unwrap();
@@ -656,50 +1287,508 @@ fn main() {
     });
 }
 
-async fn inner_main(mut quit_rx: watch::Receiver<()>) -> Result<(), Box<dyn Error>> {
-    let manager = Manager::new().await?;
+async fn handle_control(
+    request: ControlRequest,
+    sessions: &HashMap<PeripheralId, DeviceSession>,
+    pause_tx: &watch::Sender<Pause>,
+    adapters: &HashMap<String, AdapterState>,
+) -> Result<String, String> {
+    match request {
+        ControlRequest::List => {
+            let mut out = String::new();
+            for session in sessions.values() {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                if session
+                    .command_tx
+                    .send(SessionCommand::List(reply_tx))
+                    .await
+                    .is_ok()
+                {
+                    out += &reply_rx.await.unwrap_or_default();
+                }
+            }
+            Ok(out)
+        }
+        ControlRequest::Dismiss(notification_uid) | ControlRequest::Accept(notification_uid) => {
+            let accept = matches!(request, ControlRequest::Accept(_));
+            for session in sessions.values() {
+                let action_id = if accept {
+                    ActionID::Positive
+                } else {
+                    ActionID::Negative
+                };
+                let (reply_tx, reply_rx) = oneshot::channel();
+                let command = SessionCommand::PerformAction(notification_uid, action_id, reply_tx);
+                if session.command_tx.send(command).await.is_ok() {
+                    if let Ok(Some(result)) = reply_rx.await {
+                        return result.map(|_| String::new());
+                    }
+                }
+            }
+            Err(format!("no notification with UID {notification_uid}"))
+        }
+        ControlRequest::Pause(duration) => {
+            let pause = match duration {
+                Some(duration) => {
+                    Pause::Until(Instant::now().checked_add(duration).ok_or_else(|| {
+                        format!("pause duration {}s is too long", duration.as_secs())
+                    })?)
+                }
+                None => Pause::Indefinite,
+            };
+            pause_tx.send_replace(pause);
+            Ok(String::new())
+        }
+        ControlRequest::Resume => {
+            pause_tx.send_replace(Pause::Running);
+            Ok(String::new())
+        }
+        ControlRequest::Status => {
+            let mut out = match *pause_tx.borrow() {
+                Pause::Until(until) if until > Instant::now() => format!(
+                    "state\tpaused\npaused_for\t{}\n",
+                    (until - Instant::now()).as_secs()
+                ),
+                Pause::Indefinite => "state\tpaused\n".to_string(),
+                _ => "state\trunning\n".to_string(),
+            };
+            let mut adapter_infos: Vec<&str> = adapters
+                .values()
+                .map(|adapter| adapter.info.as_str())
+                .collect();
+            adapter_infos.sort();
+            for adapter_info in adapter_infos {
+                out += &format!("adapter\t{}\n", control_field(adapter_info));
+            }
+            for session in sessions.values() {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                let stats = if session
+                    .command_tx
+                    .send(SessionCommand::Stats(reply_tx))
+                    .await
+                    .is_ok()
+                {
+                    reply_rx.await.unwrap_or_default()
+                } else {
+                    SessionStats::default()
+                };
+                out += &format!(
+                    "device\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                    session.address,
+                    session.adapter,
+                    control_field(&session.label),
+                    stats
+                        .mtu
+                        .map_or("unknown".to_string(), |mtu| mtu.to_string()),
+                    stats.packets,
+                    stats.bytes,
+                    session.connected_at.elapsed().as_secs()
+                );
+            }
+            Ok(out)
+        }
+    }
+}
+
+// a Bluetooth adapter in use, and the task forwarding its events
+struct AdapterState {
+    central: Adapter,
+    info: String,
+    forwarder: tokio::task::JoinHandle<()>,
+}
 
-    // get the first bluetooth adapter
-    let adapters = manager.adapters().await?;
-    if adapters.is_empty() {
-        println!("no adapters found");
-        return Ok(());
+// the first word of the adapter info, e.g. hci0, which is what --adapter matches
+fn adapter_name(adapter_info: &str) -> &str {
+    adapter_info
+        .split_whitespace()
+        .next()
+        .unwrap_or(adapter_info)
+}
+
+async fn start_adapter(
+    central: Adapter,
+    info: String,
+    scan: bool,
+    events_tx: mpsc::Sender<(String, CentralEvent)>,
+) -> Result<AdapterState, btleplug::Error> {
+    let name = adapter_name(&info).to_string();
+    let events = central.events().await?;
+    // devices that connected before we started listening won't send DeviceConnected,
+    // so handle them as if they had (duplicates from platforms that do are ignored)
+    let mut already_connected = Vec::new();
+    for peripheral in central.peripherals().await? {
+        match peripheral.is_connected().await {
+            Ok(true) => already_connected.push(CentralEvent::DeviceConnected(peripheral.id())),
+            Ok(false) => {}
+            Err(e) => eprintln!("error checking {}: {e:?}", peripheral.address()),
+        }
+    }
+    let mut events = futures::stream::iter(already_connected).chain(events);
+    if scan {
+        // iPhones don't advertise anything to tell them by, so devices are
+        // picked from what's found by whether they're known or paired
+        central.start_scan(ScanFilter::default()).await?;
+    }
+    let forwarder = tokio::spawn(async move {
+        while let Some(event) = events.next().await {
+            if events_tx.send((name.clone(), event)).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(AdapterState {
+        central,
+        info,
+        forwarder,
+    })
+}
+
+// there are no events for adapters coming and going, so this is polled.
+// adapters that are switched off are treated as gone, and started over when they're back.
+async fn refresh_adapters(
+    centrals: Vec<Adapter>,
+    options: &Options,
+    adapters: &mut HashMap<String, AdapterState>,
+    sessions: &mut HashMap<PeripheralId, DeviceSession>,
+    events_tx: &mpsc::Sender<(String, CentralEvent)>,
+) {
+    let mut present = HashSet::new();
+    for central in centrals {
+        let Ok(info) = central.adapter_info().await else {
+            // it went away while we were looking
+            continue;
+        };
+        let name = adapter_name(&info).to_string();
+        if options
+            .adapter
+            .as_ref()
+            .is_some_and(|wanted| *wanted != name)
+        {
+            continue;
+        }
+        if matches!(central.adapter_state().await, Ok(CentralState::PoweredOff)) {
+            continue;
+        }
+        if adapters
+            .get(&name)
+            .is_some_and(|adapter| adapter.forwarder.is_finished())
+        {
+            // its events stopped, so drop it now and start over with it next time
+            continue;
+        }
+        present.insert(name.clone());
+        if adapters.contains_key(&name) {
+            continue;
+        }
+        match start_adapter(central, info.clone(), options.scan, events_tx.clone()).await {
+            Ok(adapter) => {
+                println!("using adapter {info}");
+                adapters.insert(name, adapter);
+            }
+            Err(e) => eprintln!("error using adapter {info}: {e:?}"),
+        }
+    }
+    let gone: Vec<String> = adapters
+        .keys()
+        .filter(|name| !present.contains(*name))
+        .cloned()
+        .collect();
+    for name in gone {
+        let adapter = adapters.remove(&name).unwrap();
+        println!("adapter {} is gone", adapter.info);
+        adapter.forwarder.abort();
+        // its devices went with it
+        let ids: Vec<PeripheralId> = sessions
+            .iter()
+            .filter(|(_, session)| session.adapter == name)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in ids {
+            if let Some(session) = sessions.remove(&id) {
+                let _ = session.disconnect_tx.send(());
+            }
+        }
+    }
+}
+
+// adapter_tx is told whether there are any adapters to use,
+// and devices_tx the labels of the devices being forwarded from
+async fn inner_main(
+    mut quit_rx: watch::Receiver<()>,
+    adapter_tx: watch::Sender<bool>,
+    devices_tx: watch::Sender<Vec<String>>,
+    options: Options,
+    control_listener: Option<control::ControlListener>,
+) -> Result<(), Box<dyn Error>> {
+    // created on the first poll, and again if Bluetooth itself goes away
+    let mut manager: Option<Manager> = None;
+    let mut adapters: HashMap<String, AdapterState> = HashMap::new();
+    let (events_tx, mut events_rx) = mpsc::channel(32);
+    let mut adapter_poll = tokio::time::interval(Duration::from_secs(5));
+    let mut reported_no_adapters = false;
+    // one connection for everything btleplug doesn't do
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let system = match zbus::Connection::system().await {
+        Ok(system) => Some(system),
+        Err(e) => {
+            eprintln!("error connecting to the system bus: {e:?}");
+            None
+        }
+    };
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let mut advertiser = match (&system, options.advertise) {
+        (Some(system), true) => Some(advertising::Advertiser::new(system.clone(), bluez::BLUEZ)),
+        (None, true) => {
+            eprintln!("couldn't advertise without the system bus");
+            None
+        }
+        (_, false) => None,
+    };
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    if options.advertise {
+        eprintln!("advertising is only supported with BlueZ");
     }
-    let central = &adapters[0];
-    println!("using adapter {}", central.adapter_info().await?);
 
     let mut tasks = tokio::task::JoinSet::new();
-    let mut disconnect_txs = HashMap::new();
+    let mut sessions: HashMap<PeripheralId, DeviceSession> = HashMap::new();
+    let (pause_tx, pause_rx) = watch::channel(Pause::Running);
+
+    let (control_tx, mut control_rx) = mpsc::channel(8);
+    let control_task = tokio::spawn(async move {
+        if let Some(listener) = control_listener {
+            if let Err(e) = control::serve(listener, control_tx).await {
+                eprintln!("control channel stopped: {e:?}");
+            }
+        }
+    });
+
+    // one listener for the actions on every device's desktop notifications
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let dispatcher_tx = {
+        let (dispatcher_tx, dispatcher_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            if let Err(e) = actions::action_dispatcher(dispatcher_rx).await {
+                eprintln!("action dispatcher stopped: {e:?}");
+            }
+        });
+        dispatcher_tx
+    };
+
+    // how long to leave a device alone after trying to connect to it
+    const CONNECT_RETRY: Duration = Duration::from_secs(30);
+    let mut known = known_devices::load();
+    // only BlueZ says which devices are paired
+    #[cfg(all(unix, not(target_os = "macos")))]
+    let mut paired = HashSet::new();
+    #[cfg(not(all(unix, not(target_os = "macos"))))]
+    let paired: HashSet<String> = HashSet::new();
+    let mut connects = tokio::task::JoinSet::new();
+    let mut connect_attempts: HashMap<PeripheralId, Instant> = HashMap::new();
 
-    let mut events = central.events().await?;
     loop {
         tokio::select! {
             _ = quit_rx.changed() => {
                 break;
             },
-            Some(event) = events.next() => {
-                match event {
-                    CentralEvent::DeviceConnected(id) => {
-                        let peripheral = central.peripheral(&id).await?;
-                        peripheral.discover_services().await?;
-                        if peripheral.services().iter().any(|s| s.uuid == ancs::APPLE_NOTIFICATION_CENTER_SERVICE_UUID) {
-                            let (disconnect_tx, disconnect_rx) = oneshot::channel();
-                            disconnect_txs.insert(id.clone(), disconnect_tx);
-                            tasks.spawn(watch_device(peripheral, quit_rx.clone(), disconnect_rx));
+            _ = adapter_poll.tick() => {
+                if manager.is_none() {
+                    manager = match Manager::new().await {
+                        Ok(manager) => Some(manager),
+                        Err(e) => {
+                            if !reported_no_adapters {
+                                eprintln!("error starting Bluetooth: {e:?}");
+                            }
+                            None
                         }
+                    };
+                }
+                let centrals = match &manager {
+                    Some(manager) => manager.adapters().await,
+                    None => Ok(Vec::new()),
+                };
+                let centrals = centrals.unwrap_or_else(|e| {
+                    if !reported_no_adapters {
+                        eprintln!("error listing adapters: {e:?}");
                     }
-                    CentralEvent::DeviceDisconnected(id) => {
-                        if let Some(disconnect_tx) = disconnect_txs.remove(&id) {
-                            disconnect_tx.send(()).unwrap();
+                    manager = None;
+                    Vec::new()
+                });
+                refresh_adapters(centrals, &options, &mut adapters, &mut sessions, &events_tx).await;
+                #[cfg(all(unix, not(target_os = "macos")))]
+                if let Some(advertiser) = &mut advertiser {
+                    advertiser.update(adapters.keys()).await;
+                }
+                #[cfg(all(unix, not(target_os = "macos")))]
+                if let (true, Some(system)) = (options.scan, &system) {
+                    // so devices paired since, e.g. from the tray, are tried too
+                    paired = known_devices::paired(system).await;
+                }
+                if adapters.is_empty() && !reported_no_adapters {
+                    println!("no adapters found, waiting for one");
+                }
+                reported_no_adapters = adapters.is_empty();
+                let available = !adapters.is_empty();
+                adapter_tx.send_if_modified(|was_available| {
+                    std::mem::replace(was_available, available) != available
+                });
+            },
+            Some((adapter_name, event)) = events_rx.recv() => {
+                let Some(central) = adapters.get(&adapter_name).map(|adapter| &adapter.central) else {
+                    // left over from an adapter that's gone
+                    continue;
+                };
+                // errors here are usually the device or adapter going away, which isn't fatal
+                let result: Result<(), btleplug::Error> = async {
+                    match event {
+                        CentralEvent::StateUpdate(state) => {
+                            if state == CentralState::PoweredOff {
+                                println!("adapter {adapter_name} was switched off");
+                            }
+                            // pick up the change now rather than at the next poll
+                            adapter_poll.reset_immediately();
+                        }
+                        CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id)
+                            if options.scan
+                                && !sessions.contains_key(&id)
+                                // rules out most of what's around before asking the adapter anything
+                                && (known.contains(&id.to_string()) || paired.contains(&id.to_string()))
+                                && connect_attempts
+                                    .get(&id)
+                                    .is_none_or(|attempt| attempt.elapsed() >= CONNECT_RETRY) =>
+                        {
+                            let peripheral = central.peripheral(&id).await?;
+                            if !options.allows(&device_names(&peripheral).await) {
+                                return Ok(());
+                            }
+                            connect_attempts.insert(id.clone(), Instant::now());
+                            connects.spawn(async move {
+                                if !peripheral.is_connected().await? {
+                                    println!("connecting to {}", peripheral.address());
+                                    // DeviceConnected follows once it's up
+                                    peripheral.connect().await?;
+                                }
+                                Ok::<_, btleplug::Error>(())
+                            });
                         }
+                        CentralEvent::DeviceConnected(id) if !sessions.contains_key(&id) => {
+                            let peripheral = central.peripheral(&id).await?;
+                            let names = device_names(&peripheral).await;
+                            let label = options.label(&names);
+                            let display_label = label.clone().unwrap_or_else(|| peripheral.address().to_string());
+                            if !options.allows(&names) {
+                                println!("ignoring {display_label}");
+                                return Ok(());
+                            }
+                            peripheral.discover_services().await?;
+                            if peripheral.services().iter().any(|s| s.uuid == ancs::APPLE_NOTIFICATION_CENTER_SERVICE_UUID) {
+                                let (disconnect_tx, disconnect_rx) = oneshot::channel();
+                                let (command_tx, command_rx) = mpsc::channel(8);
+                                let context = SessionContext {
+                                    quit_rx: quit_rx.clone(),
+                                    disconnect_rx,
+                                    command_rx,
+                                    pause_rx: pause_rx.clone(),
+                                    #[cfg(all(unix, not(target_os = "macos")))]
+                                    options: options.clone(),
+                                    label,
+                                    #[cfg(all(unix, not(target_os = "macos")))]
+                                    system: system.clone(),
+                                    #[cfg(all(unix, not(target_os = "macos")))]
+                                    desktop_actions: DesktopActions::new(dispatcher_tx.clone(), command_tx.clone()),
+                                };
+                                sessions.insert(id.clone(), DeviceSession {
+                                    address: peripheral.address().to_string(),
+                                    label: display_label,
+                                    connected_at: Instant::now(),
+                                    adapter: adapter_name.clone(),
+                                    disconnect_tx,
+                                    command_tx,
+                                });
+                                let task_id = id.clone();
+                                tasks.spawn(async move { (task_id, watch_device(peripheral, context).await) });
+                                if known.insert(id.to_string()) {
+                                    if let Err(e) = known_devices::save(&known) {
+                                        eprintln!("error saving known devices: {e:?}");
+                                    }
+                                }
+                            }
+                        }
+                        CentralEvent::DeviceDisconnected(id) => {
+                            if let Some(session) = sessions.remove(&id) {
+                                // the task may have already ended on its own
+                                let _ = session.disconnect_tx.send(());
+                            }
+                        }
+                        _ => {}
                     }
-                    _ => {}
+                    Ok(())
+                }
+                .await;
+                if let Err(e) = result {
+                    eprintln!("error handling {adapter_name} event: {e:?}");
                 }
             },
+            Some(res) = connects.join_next() => {
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => eprintln!("error connecting: {e:?}"),
+                    // a panic in there shouldn't take everything else down
+                    Err(e) => eprintln!("error connecting: {e:?}"),
+                }
+            },
+            Some(res) = tasks.join_next() => {
+                match res {
+                    Ok((id, result)) => {
+                        let label = sessions.get(&id).map_or_else(|| id.to_string(), |session| session.label.clone());
+                        if let Err(e) = result {
+                            eprintln!("error forwarding from {label}: {e:?}");
+                        }
+                        // unless it's already been replaced by a new connection, whose task is still running
+                        if sessions.get(&id).is_some_and(|session| session.command_tx.is_closed()) {
+                            sessions.remove(&id);
+                        }
+                    }
+                    // a panic in there shouldn't take everything else down
+                    Err(e) => eprintln!("error forwarding: {e:?}"),
+                }
+            },
+            Some(message) = control_rx.recv() => {
+                let reply = handle_control(message.request, &sessions, &pause_tx, &adapters).await;
+                let _ = message.reply.send(reply);
+            },
+        }
+        let mut labels: Vec<String> = sessions
+            .values()
+            .map(|session| session.label.clone())
+            .collect();
+        labels.sort();
+        devices_tx.send_if_modified(|old_labels| {
+            if *old_labels == labels {
+                return false;
+            }
+            *old_labels = labels;
+            true
+        });
+    }
+    control_task.abort();
+    #[cfg(all(unix, not(target_os = "macos")))]
+    if let Some(advertiser) = &mut advertiser {
+        advertiser.update([]).await;
+    }
+    for adapter in adapters.values() {
+        adapter.forwarder.abort();
+        if options.scan {
+            if let Err(e) = adapter.central.stop_scan().await {
+                eprintln!("error stopping scan on {}: {e:?}", adapter.info);
+            }
         }
     }
     while let Some(res) = tasks.join_next().await {
-        res??;
+        match res {
+            Ok((_, Ok(()))) => {}
+            Ok((id, Err(e))) => eprintln!("error forwarding from {id}: {e:?}"),
+            Err(e) => eprintln!("error forwarding: {e:?}"),
+        }
     }
     Ok(())
 }
@@ -1,8 +1,12 @@
 use async_zip::tokio::write::ZipFileWriter;
-use async_zip::{AttributeCompatibility, Compression, ZipEntryBuilder};
+use async_zip::{
+    AttributeCompatibility, Compression, ZipDateTime, ZipDateTimeBuilder, ZipEntryBuilder,
+};
 use clap::{arg, command, value_parser};
 use derive_more::From;
 use iconwriter::{icns, Icon, IconError, Image};
+use md5::Digest;
+use sha2::Sha256;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use tokio::fs;
@@ -17,15 +21,19 @@ macro_rules! INFO_PLIST_FMT { () => { "<?xml version=\"1.0\" encoding=\"UTF-8\"?
     <key>CFBundleInfoDictionaryVersion</key>
     <string>6.0</string>
     <key>NSBluetoothAlwaysUsageDescription</key>
-    <string>Connecting to your device and receive its notifications</string>
+    <string>{}</string>
     <key>CFBundleExecutable</key>
     <string>{}</string>
     <key>CFBundleIconFile</key>
     <string>Icon.icns</string>
     <key>CFBundleIdentifier</key>
-    <string>net.boatcake.{}</string>
+    <string>{}</string>
+    <key>CFBundleName</key>
+    <string>{}</string>
     <key>CFBundlePackageType</key>
     <string>APPL</string>
+    <key>CFBundleShortVersionString</key>
+    <string>{}</string>
     <key>CFBundleVersion</key>
     <string>{}</string>
     <key>LSUIElement</key>
@@ -35,40 +43,383 @@ macro_rules! INFO_PLIST_FMT { () => { "<?xml version=\"1.0\" encoding=\"UTF-8\"?
 </dict>
 </plist>" }; }
 
-macro_rules! DESKTOP_ENTRY_FMT {
+// what macOS shows when asking the user to allow Bluetooth, which it won't
+// allow at all, hardened runtime or not, if the app doesn't have one
+const BLUETOOTH_USAGE: &str = "Connects to your device to receive its notifications";
+
+// for software centers, which show the package's description as a one-line summary
+const LONG_DESCRIPTION: &str =
+    "Receives the notifications an iPhone or iPad shows, over Bluetooth, \
+and shows them on the desktop, from a tray icon. Notifications that can be answered or declined on \
+the device can be from the desktop as well.";
+
+// for signing; the Bluetooth entitlement only matters if the app is also
+// sandboxed, otherwise access comes from the usage description above
+const ENTITLEMENTS: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">
+<plist version=\"1.0\">
+<dict>
+    <key>com.apple.security.device.bluetooth</key>
+    <true/>
+</dict>
+</plist>";
+
+include!("../../desktop_entry.rs");
+include!("../../app_id.rs");
+mod exit_status {
+    include!("../../exit_status.rs");
+}
+
+macro_rules! DEB_CONTROL_FMT {
+    () => {
+        "Package: {}
+Version: {}
+Architecture: {}
+Maintainer: {}
+Installed-Size: {}
+Depends: {}
+Section: utils
+Priority: optional
+Description: {}
+"
+    };
+}
+
+macro_rules! RPM_SPEC_FMT {
+    () => {
+        "Name: {}
+Version: {}
+Release: 1
+Summary: {}
+License: {}
+URL: {}
+Requires: {}
+# the binary is already built, and its dependencies are listed above
+AutoReqProv: no
+%global debug_package %{{nil}}
+
+%description
+{}
+
+%install
+cp -a {}/. %{{buildroot}}/
+
+%files
+{}"
+    };
+}
+
+// runs the app in the background for the desktop user, after `systemctl --user enable`
+macro_rules! SYSTEMD_UNIT_FMT {
     () => {
-        "[Desktop Entry]
-Type=Application
-Version=1.5
+        "[Unit]
+Description={}
+
+[Service]
+ExecStart={} --headless
+Restart=on-failure
+RestartSec=5
+# the tray app is already running, which would be the same every time
+RestartPreventExitStatus={}
+SuccessExitStatus={}
+
+[Install]
+WantedBy=default.target
+"
+    };
+}
+
+// starts the service when something calls the app's bus name
+macro_rules! DBUS_SERVICE_FMT {
+    () => {
+        "[D-BUS Service]
 Name={}
-Icon={}
-Categories=Utility
-Exec={}
-Terminal=false
-X-GNOME-UsesNotifications=true
+Exec={}/{} --headless
+SystemdService={}.service
+"
+    };
+}
+
+macro_rules! APPRUN_FMT {
+    () => {
+        "#!/bin/sh
+HERE=\"$(dirname \"$(readlink -f \"$0\")\")\"
+exec \"$HERE/usr/bin/{}\" \"$@\"
+"
+    };
+}
+
+macro_rules! FLATPAK_MANIFEST_FMT {
+    () => {
+        "app-id: {app_id}
+runtime: org.gnome.Platform
+runtime-version: '47'
+sdk: org.gnome.Sdk
+sdk-extensions:
+  - org.freedesktop.Sdk.Extension.rust-stable
+command: {name}
+finish-args:
+  - --share=ipc
+  - --socket=fallback-x11
+  - --socket=wayland
+  # Bluetooth LE devices
+  - --system-talk-name=org.bluez
+  # desktop notifications and their actions
+  - --talk-name=org.freedesktop.Notifications
+  # tray icon
+  - --talk-name=org.kde.StatusNotifierWatcher
+modules:
+  # from https://github.com/flathub/shared-modules
+  - shared-modules/libappindicator/libappindicator-gtk3-12.10.json
+  - name: libxdo
+    no-autogen: true
+    make-install-args:
+      - PREFIX=/app
+    sources:
+      - type: git
+        url: https://github.com/jordansissel/xdotool.git
+        tag: v3.20211022.1
+  - name: {name}
+    buildsystem: simple
+    build-options:
+      append-path: /usr/lib/sdk/rust-stable/bin
+      env:
+        # where cargo-sources.json puts the vendored crates and their config
+        CARGO_HOME: /run/build/{name}/cargo
+    build-commands:
+      - cargo --offline build --release --locked
+      - install -Dm755 target/release/{name} -t /app/bin/
+      - cp -a files/. /app/
+    sources:
+      - type: dir
+        path: {source}
+        skip:
+          - target
+      # from flatpak-cargo-generator.py, for building without the network
+      - cargo-sources.json
+      - type: dir
+        path: files
+        dest: files
+"
+    };
+}
+
+macro_rules! METAINFO_FMT {
+    () => {
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<component type=\"desktop-application\">
+  <id>{}</id>
+  <metadata_license>CC0-1.0</metadata_license>
+  <project_license>{}</project_license>
+  <name>{}</name>
+  <summary>{}</summary>
+  <description>
+    <p>{}</p>
+  </description>
+  <launchable type=\"desktop-id\">{}.desktop</launchable>
+  <releases>
+    <release version=\"{}\"/>
+  </releases>
+</component>
+"
+    };
+}
+
+macro_rules! WIX_SOURCE_FMT {
+    () => {
+        "<Wix xmlns=\"http://wixtoolset.org/schemas/v4/wxs\">
+  <Package Name=\"{name}\" Manufacturer=\"{manufacturer}\" Version=\"{version}\" UpgradeCode=\"{upgrade_code}\" Scope=\"perMachine\">
+    <MajorUpgrade DowngradeErrorMessage=\"A newer version of [ProductName] is already installed.\" />
+    <MediaTemplate EmbedCab=\"yes\" />
+
+    <StandardDirectory Id=\"ProgramFiles6432Folder\">
+      <Directory Id=\"INSTALLFOLDER\" Name=\"{name}\">
+        <Component Id=\"Executable\">
+          <File Id=\"Executable\" Source=\"{exename}\" KeyPath=\"yes\" />
+        </Component>
+      </Directory>
+    </StandardDirectory>
+
+    <StandardDirectory Id=\"ProgramMenuFolder\">
+      <Component Id=\"StartMenuShortcut\">
+        <!-- notifications are attributed to the shortcut with the same app ID -->
+        <Shortcut Id=\"StartMenuShortcut\" Name=\"{name}\" Target=\"[INSTALLFOLDER]{exename}\" WorkingDirectory=\"INSTALLFOLDER\">
+          <ShortcutProperty Key=\"System.AppUserModel.ID\" Value=\"{app_user_model_id}\" />
+        </Shortcut>
+        <RegistryValue Root=\"HKLM\" Key=\"Software\\{name}\" Name=\"StartMenuShortcut\" Type=\"integer\" Value=\"1\" KeyPath=\"yes\" />
+      </Component>
+    </StandardDirectory>
+
+    <!-- like the app's \"Start at login\" menu item, but for every user, as it's installed for every user -->
+    <Component Id=\"Autostart\" Directory=\"INSTALLFOLDER\">
+      <RegistryValue Root=\"HKLM\" Key=\"Software\\Microsoft\\Windows\\CurrentVersion\\Run\" Name=\"{name}\" Type=\"string\" Value=\"&quot;[INSTALLFOLDER]{exename}&quot;\" KeyPath=\"yes\" />
+    </Component>
+
+    <Feature Id=\"Main\" Title=\"{name}\" AllowAbsent=\"no\">
+      <ComponentRef Id=\"Executable\" />
+      <ComponentRef Id=\"StartMenuShortcut\" />
+    </Feature>
+    <!-- installed with AUTOSTART=1 -->
+    <Feature Id=\"Autostart\" Title=\"Start at login\" Level=\"2\">
+      <Level Value=\"1\" Condition=\"AUTOSTART = 1\" />
+      <ComponentRef Id=\"Autostart\" />
+    </Feature>
+  </Package>
+</Wix>
+"
+    };
+}
+
+// identifies the product across versions, so installing a new one replaces the old
+const WIX_UPGRADE_CODE: &str = "79027D44-6B9B-4CE8-873A-CFDD65EEF9F3";
+
+macro_rules! BUILD_MANIFEST_FMT {
+    () => {
+        "{{
+  \"name\": {},
+  \"version\": {},
+  \"target\": {},
+  \"format\": {},
+  \"git_commit\": {},
+  \"rustc\": {},
+  \"builder\": {},
+  \"profile\": {},
+  \"features\": [{}]
+}}
+"
+    };
+}
+
+macro_rules! CYCLONEDX_FMT {
+    () => {
+        "{{
+  \"bomFormat\": \"CycloneDX\",
+  \"specVersion\": \"1.5\",
+  \"version\": 1,
+  \"metadata\": {{
+    \"component\": {}
+  }},
+  \"components\": [{}
+  ],
+  \"dependencies\": [{}
+  ]
+}}
 "
     };
 }
 
+// runtime libraries for the tray icon and desktop integration
+const DEB_DEPENDS: &str = "libgtk-3-0, libxdo3, libayatana-appindicator3-1 | libappindicator3-1";
+const RPM_REQUIRES: &str = "gtk3, libxdo, (libayatana-appindicator-gtk3 or libappindicator-gtk3)";
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn maintainer(pkg_info: &toml::Value) -> String {
+    pkg_info
+        .get("authors")
+        .and_then(|authors| authors.get(0))
+        .and_then(|author| author.as_str())
+        .map_or_else(
+            || format!("{} developers", pkg_info["name"].as_str().unwrap()),
+            str::to_owned,
+        )
+}
+
+// reverse-DNS name for the bundle identifier and Flatpak app ID, unless one is given
+fn default_app_id(pkg_name: &str) -> String {
+    format!("net.boatcake.{pkg_name}")
+}
+
+fn valid_app_id(app_id: &str) -> bool {
+    app_id.split('.').count() >= 2
+        && app_id.split('.').all(|part| {
+            !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
 #[derive(Debug, From)]
 enum Error {
     Io(io::Error),
     Zip(async_zip::error::ZipError),
     Icns(IconError<icns::Key>),
     Toml(toml::de::Error),
+    Plist(plist::Error),
+    Png(png::EncodingError),
     Triple(target_spec::errors::TripleParseError),
     TargetSpec(target_spec::Error),
+    #[from(ignore)]
+    Unsupported(String),
+    #[from(ignore)]
+    Usage(String),
+    #[from(ignore)]
+    Render(String),
+    #[from(ignore)]
+    InvalidPlist(String),
+    #[from(ignore)]
+    Build(std::process::ExitStatus),
+    #[from(ignore)]
+    Tool(&'static str, std::process::ExitStatus),
+    #[from(ignore)]
+    MissingTool(&'static str, PathBuf),
+    #[from(ignore)]
+    NotBuilt(String),
+    #[from(ignore)]
+    Verify(String),
+    #[from(ignore)]
+    Failed(usize, usize),
 }
 
-#[cfg(windows)]
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Zip(e) => write!(f, "writing zip: {e}"),
+            Error::Icns(e) => write!(f, "making icon: {e:?}"),
+            Error::Toml(e) => write!(f, "reading manifest: {e}"),
+            Error::Plist(e) => write!(f, "checking plist: {e}"),
+            Error::Png(e) => write!(f, "writing icon: {e}"),
+            Error::Triple(e) => write!(f, "{e}"),
+            Error::TargetSpec(e) => write!(f, "{e}"),
+            Error::Unsupported(message) => write!(f, "{message}"),
+            Error::Usage(message) => write!(f, "{message}"),
+            Error::Render(message) => write!(f, "making icon: {message}"),
+            Error::InvalidPlist(message) => write!(f, "checking plist: {message}"),
+            Error::Build(status) => write!(f, "build failed with {status}"),
+            Error::Tool(tool, status) => write!(f, "{tool} failed with {status}"),
+            Error::MissingTool(tool, dir) => write!(
+                f,
+                "{tool} not found, install it or build the package from {dir:?} instead"
+            ),
+            Error::NotBuilt(message) => write!(f, "{message}"),
+            Error::Verify(message) => write!(f, "verifying: {message}"),
+            Error::Failed(failed, total) => write!(f, "{failed} of {total} targets failed"),
+        }
     }
 }
 
 trait IoResultExt {
     fn exist_ok(self) -> Self;
+    fn not_found_ok(self) -> Self;
 }
 
 impl IoResultExt for io::Result<()> {
@@ -78,10 +429,1296 @@ impl IoResultExt for io::Result<()> {
             _ => self,
         }
     }
+
+    fn not_found_ok(self) -> io::Result<()> {
+        match self {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            _ => self,
+        }
+    }
+}
+
+// a file to be packaged, at its path relative to the install location
+struct StagedFile {
+    path: String,
+    data: Vec<u8>,
+    mode: u32,
+}
+
+impl StagedFile {
+    fn new(path: String, data: Vec<u8>) -> Self {
+        StagedFile {
+            path,
+            data,
+            mode: 0o644,
+        }
+    }
+
+    fn executable(path: String, data: Vec<u8>) -> Self {
+        StagedFile {
+            path,
+            data,
+            mode: 0o755,
+        }
+    }
+}
+
+async fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path).await?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).await?;
+    Ok(data)
+}
+
+// what to build with, and how
+struct BuildOptions {
+    builder: String,
+    profile: String,
+    features: Vec<String>,
+    cargo_args: Vec<String>,
+}
+
+impl BuildOptions {
+    // where the binary ends up, under target/<triple>
+    fn profile_dir(&self) -> &str {
+        match self.profile.as_str() {
+            "dev" | "test" => "debug",
+            "bench" => "release",
+            profile => profile,
+        }
+    }
+}
+
+// parse a generated plist back, and check it holds the given strings
+fn verify_plist(data: &[u8], expected: &[(&str, &str)]) -> Result<(), Error> {
+    let value = plist::Value::from_reader_xml(data)?;
+    let dict = value
+        .as_dictionary()
+        .ok_or_else(|| Error::InvalidPlist("it is not a dictionary".to_string()))?;
+    for (key, expected_value) in expected {
+        let value = dict.get(key).and_then(|value| value.as_string());
+        if value != Some(*expected_value) {
+            return Err(Error::InvalidPlist(format!(
+                "it has {key} {value:?} instead of {expected_value:?}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+async fn stage_macos(
+    manifest_dir: &Path,
+    build_dir: &Path,
+    pkg_name: &str,
+    pkg_version: &str,
+    app_id: &str,
+) -> Result<Vec<StagedFile>, Error> {
+    let exedata = read_file(&build_dir.join(pkg_name)).await?;
+
+    let mut icon = icns::Icns::new();
+    icon.add_entries(
+        iconwriter::resample::linear,
+        &Image::open(manifest_dir.join("icon.svg"))?,
+        vec![
+            icns::Key::Rgba16,
+            icns::Key::Rgba32,
+            icns::Key::Rgba64,
+            icns::Key::Rgba128,
+            icns::Key::Rgba256,
+            icns::Key::Rgba512,
+            icns::Key::Rgba1024,
+        ],
+    )?;
+    let mut icondata = Vec::new();
+    icon.write(&mut icondata)?;
+
+    let info_plist = format!(
+        INFO_PLIST_FMT!(),
+        BLUETOOTH_USAGE, pkg_name, app_id, pkg_name, pkg_version, pkg_version
+    );
+    verify_plist(
+        info_plist.as_bytes(),
+        &[
+            ("NSBluetoothAlwaysUsageDescription", BLUETOOTH_USAGE),
+            ("CFBundleExecutable", pkg_name),
+            ("CFBundleIdentifier", app_id),
+            ("CFBundleName", pkg_name),
+            ("CFBundleShortVersionString", pkg_version),
+            ("CFBundleVersion", pkg_version),
+        ],
+    )?;
+
+    Ok(vec![
+        StagedFile::executable(format!("{pkg_name}.app/Contents/MacOS/{pkg_name}"), exedata),
+        StagedFile::new(
+            format!("{pkg_name}.app/Contents/Resources/Icon.icns"),
+            icondata,
+        ),
+        StagedFile::new(
+            format!("{pkg_name}.app/Contents/Info.plist"),
+            info_plist.into_bytes(),
+        ),
+    ])
+}
+
+async fn stage_windows(build_dir: &Path, pkg_name: &str) -> Result<Vec<StagedFile>, Error> {
+    let exename = format!("{pkg_name}.exe");
+    let exedata = read_file(&build_dir.join(&exename)).await?;
+    Ok(vec![StagedFile::executable(exename, exedata)])
+}
+
+// sizes of the raster icons for desktops that don't use the SVG
+const HICOLOR_SIZES: [u32; 8] = [16, 24, 32, 48, 64, 128, 256, 512];
+
+// icon themes recolour symbolic icons from this
+const SYMBOLIC_COLOR: &str = "#2e3436";
+
+fn render_png(image: &Image, size: u32) -> Result<Vec<u8>, Error> {
+    let rgba = image
+        .rasterize(iconwriter::resample::linear, size)
+        .map_err(|e| Error::Render(format!("rendering {size}px icon: {e:?}")))?;
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, rgba.width(), rgba.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(rgba.as_raw())?;
+    Ok(data)
+}
+
+// properties that set colours, which symbolic icons have in only one
+const SYMBOLIC_PROPERTIES: [&str; 4] = ["fill", "stroke", "color", "stop-color"];
+
+fn symbolic_color(value: &str) -> &str {
+    let value = value.trim();
+    if matches!(value, "none" | "currentColor") || value.starts_with("url(") {
+        value
+    } else {
+        SYMBOLIC_COLOR
+    }
+}
+
+// the declarations of a style attribute, with the colours replaced
+fn symbolic_style(style: &str) -> String {
+    style
+        .split(';')
+        .map(|declaration| match declaration.split_once(':') {
+            Some((property, value)) if SYMBOLIC_PROPERTIES.contains(&property.trim()) => {
+                format!("{property}:{}", symbolic_color(value))
+            }
+            _ => declaration.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+// The same drawing with every fill, stroke and gradient stop in the symbolic
+// colour. Only colours in attributes and style attributes are replaced, so
+// style sheets are refused rather than left in colour.
+fn symbolic_svg(svg: &str) -> Result<String, Error> {
+    if svg.contains("<style") {
+        return Err(Error::Unsupported(
+            "symbolic icons can't be made from SVGs with <style> elements".to_string(),
+        ));
+    }
+    let mut out = String::with_capacity(svg.len());
+    let mut rest = svg;
+    // each attribute, as name="value" or name='value'
+    while let Some(index) = rest.find('=') {
+        let (before, after) = rest.split_at(index);
+        out.push_str(before);
+        out.push('=');
+        rest = &after[1..];
+        let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        let Some(end) = rest[1..].find(quote) else {
+            break;
+        };
+        let value = &rest[1..end + 1];
+        let name = before
+            .rsplit(|c: char| !c.is_ascii_alphanumeric() && c != '-' && c != ':')
+            .next()
+            .unwrap_or_default();
+        out.push(quote);
+        if name == "style" {
+            out.push_str(&symbolic_style(value));
+        } else if SYMBOLIC_PROPERTIES.contains(&name) {
+            out.push_str(symbolic_color(value));
+        } else {
+            out.push_str(value);
+        }
+        out.push(quote);
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+async fn stage_unix(
+    manifest_dir: &Path,
+    build_dir: &Path,
+    pkg_info: &toml::Value,
+    // where the executable is installed, if D-Bus should start the service
+    dbus_activation: Option<&str>,
+) -> Result<Vec<StagedFile>, Error> {
+    let pkg_name = pkg_info["name"].as_str().unwrap();
+    let exedata = read_file(&build_dir.join(pkg_name)).await?;
+    let iconpath = manifest_dir.join("icon.svg");
+    let icondata = read_file(&iconpath).await?;
+    let mut files = vec![
+        StagedFile::executable(format!("bin/{pkg_name}"), exedata),
+        StagedFile::new(
+            format!("share/icons/hicolor/symbolic/apps/{pkg_name}-symbolic.svg"),
+            symbolic_svg(&String::from_utf8_lossy(&icondata))?.into_bytes(),
+        ),
+        StagedFile::new(
+            format!("share/icons/hicolor/scalable/apps/{pkg_name}.svg"),
+            icondata,
+        ),
+        StagedFile::new(
+            format!("share/applications/{pkg_name}.desktop"),
+            format!(DESKTOP_ENTRY_FMT!(), pkg_name, pkg_name, pkg_name).into_bytes(),
+        ),
+        StagedFile::new(
+            format!("lib/systemd/user/{pkg_name}.service"),
+            format!(
+                SYSTEMD_UNIT_FMT!(),
+                pkg_info
+                    .get("description")
+                    .and_then(|d| d.as_str())
+                    .unwrap_or(pkg_name),
+                // found on systemd's own search path, wherever it's installed
+                pkg_name,
+                exit_status::ALREADY_RUNNING,
+                exit_status::ALREADY_RUNNING
+            )
+            .into_bytes(),
+        ),
+    ];
+    let image = Image::open(&iconpath)?;
+    for size in HICOLOR_SIZES {
+        files.push(StagedFile::new(
+            format!("share/icons/hicolor/{size}x{size}/apps/{pkg_name}.png"),
+            render_png(&image, size)?,
+        ));
+    }
+    if let Some(bin_dir) = dbus_activation {
+        // the name the app itself asks for, whatever the package's app ID
+        let bus_name = default_app_id(pkg_name);
+        files.push(StagedFile::new(
+            format!("share/dbus-1/services/{bus_name}.service"),
+            format!(DBUS_SERVICE_FMT!(), bus_name, bin_dir, pkg_name, pkg_name).into_bytes(),
+        ));
+    }
+    Ok(files)
+}
+
+async fn stage(
+    release: &Release<'_>,
+    build_dir: &Path,
+    dbus_activation: Option<&str>,
+) -> Result<Vec<StagedFile>, Error> {
+    let Release {
+        manifest_dir,
+        pkg_info,
+        app_id,
+        target,
+        ..
+    } = *release;
+    let pkg_name = pkg_info["name"].as_str().unwrap();
+    let pkg_version = pkg_info["version"].as_str().unwrap();
+    let mut files = if target_spec::eval("cfg(target_os = \"macos\")", target)?.unwrap() {
+        stage_macos(manifest_dir, build_dir, pkg_name, pkg_version, app_id).await?
+    } else if target_spec::eval("cfg(windows)", target)?.unwrap() {
+        stage_windows(build_dir, pkg_name).await?
+    } else if target_spec::eval("cfg(unix)", target)?.unwrap() {
+        stage_unix(manifest_dir, build_dir, pkg_info, dbus_activation).await?
+    } else {
+        Vec::new()
+    };
+    // packages list files in the same order every time
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+// SOURCE_DATE_EPOCH if it's set, or else the time of the last commit,
+// so that packaging the same commit twice gives the same timestamps
+async fn source_date_epoch(manifest_dir: &Path) -> Option<u64> {
+    if let Some(epoch) = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+    {
+        return Some(epoch);
+    }
+    command_output("git", &["log", "-1", "--format=%ct"], manifest_dir)
+        .await
+        .and_then(|epoch| epoch.parse().ok())
+}
+
+// MS-DOS date and time, which can't go before 1980
+fn zip_date_time(epoch: u64) -> ZipDateTime {
+    let seconds = epoch % 86400;
+    // civil_from_days from http://howardhinnant.github.io/date_algorithms.html
+    let days = (epoch / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    if year < 1980 {
+        return ZipDateTimeBuilder::new().year(1980).month(1).day(1).build();
+    }
+    ZipDateTimeBuilder::new()
+        .year(year.min(2107) as i32)
+        .month(month as u32)
+        .day(day as u32)
+        .hour((seconds / 3600) as u32)
+        .minute((seconds / 60 % 60) as u32)
+        .second((seconds % 60) as u32)
+        .build()
+}
+
+async fn write_zip(zipname: &str, files: &[StagedFile], mtime: u64) -> Result<(), Error> {
+    println!("Zipping to {zipname}");
+    let mut zipfile = fs::File::create(zipname).await?;
+    let mut zipwriter = ZipFileWriter::with_tokio(&mut zipfile);
+    let date = zip_date_time(mtime);
+    for file in files {
+        let entry = ZipEntryBuilder::new(file.path.clone().into(), Compression::Deflate)
+            .last_modification_date(date)
+            .attribute_compatibility(AttributeCompatibility::Unix)
+            // as a regular file
+            .unix_permissions((0o100000 | file.mode) as u16);
+        zipwriter
+            .write_entry_whole(entry.build(), &file.data)
+            .await?;
+    }
+    zipwriter.close().await?;
+    Ok(())
+}
+
+// anything left from packaging before would be packaged again
+async fn write_dir(dir: &Path, files: &[StagedFile]) -> io::Result<()> {
+    fs::remove_dir_all(dir).await.not_found_ok()?;
+    for file in files {
+        let path = dir.join(&file.path);
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        fs::write(&path, &file.data).await?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, std::fs::Permissions::from_mode(file.mode)).await?;
+        }
+    }
+    Ok(())
+}
+
+fn deb_architecture(target: &str) -> Option<&'static str> {
+    match target.split('-').next()? {
+        "x86_64" => Some("amd64"),
+        "aarch64" => Some("arm64"),
+        "i586" | "i686" => Some("i386"),
+        "armv7" => Some("armhf"),
+        "arm" => Some("armel"),
+        "riscv64gc" => Some("riscv64"),
+        "powerpc64le" => Some("ppc64el"),
+        _ => None,
+    }
+}
+
+// gzipped tarball of the given files, along with the directories leading to them
+fn tar_gz(files: &[(String, &[u8], u32)], mtime: u64) -> io::Result<Vec<u8>> {
+    let mut dirs = std::collections::BTreeSet::new();
+    for (path, _, _) in files {
+        let mut parent = Path::new(path).parent();
+        while let Some(dir) = parent.filter(|dir| !dir.as_os_str().is_empty()) {
+            dirs.insert(dir.to_path_buf());
+            parent = dir.parent();
+        }
+    }
+
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        Vec::new(),
+        flate2::Compression::best(),
+    ));
+    for dir in dirs {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_mtime(mtime);
+        header.set_size(0);
+        builder.append_data(&mut header, dir, std::io::empty())?;
+    }
+    for (path, data, mode) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(*mode);
+        header.set_mtime(mtime);
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, path, *data)?;
+    }
+    builder.into_inner()?.finish()
+}
+
+fn write_deb(
+    debname: &str,
+    files: &[StagedFile],
+    pkg_info: &toml::Value,
+    target: &str,
+    mtime: u64,
+) -> Result<(), Error> {
+    println!("Writing Debian package to {debname}");
+    let architecture = deb_architecture(target)
+        .ok_or_else(|| Error::Unsupported(format!("no Debian architecture for {target}")))?;
+
+    // installed the same way the README describes for the zip, but into /usr
+    let data_files: Vec<(String, &[u8], u32)> = files
+        .iter()
+        .map(|file| {
+            (
+                format!("usr/{}", file.path),
+                file.data.as_slice(),
+                file.mode,
+            )
+        })
+        .collect();
+    let md5sums: String = data_files
+        .iter()
+        .map(|(path, data, _)| format!("{:x}  {path}\n", md5::Md5::digest(data)))
+        .collect();
+    let installed_size = files
+        .iter()
+        .map(|file| file.data.len() as u64)
+        .sum::<u64>()
+        .div_ceil(1024);
+
+    let pkg_name = pkg_info["name"].as_str().unwrap();
+    let mut control = format!(
+        DEB_CONTROL_FMT!(),
+        pkg_name,
+        pkg_info["version"].as_str().unwrap(),
+        architecture,
+        maintainer(pkg_info),
+        installed_size,
+        DEB_DEPENDS,
+        pkg_info
+            .get("description")
+            .and_then(|d| d.as_str())
+            .unwrap_or(pkg_name),
+    );
+    if let Some(homepage) = pkg_info.get("repository").and_then(|r| r.as_str()) {
+        control += &format!("Homepage: {homepage}\n");
+    }
+
+    let control_tar = tar_gz(
+        &[
+            ("control".to_string(), control.as_bytes(), 0o644),
+            ("md5sums".to_string(), md5sums.as_bytes(), 0o644),
+        ],
+        mtime,
+    )?;
+    let data_tar = tar_gz(&data_files, mtime)?;
+
+    let mut ar = ar::Builder::new(std::fs::File::create(debname)?);
+    for (identifier, data) in [
+        ("debian-binary", b"2.0\n".as_slice()),
+        ("control.tar.gz", &control_tar),
+        ("data.tar.gz", &data_tar),
+    ] {
+        let mut header = ar::Header::new(identifier.as_bytes().to_vec(), data.len() as u64);
+        header.set_mtime(mtime);
+        header.set_mode(0o644);
+        ar.append(&header, data)?;
+    }
+    Ok(())
+}
+
+fn rpm_architecture(target: &str) -> Option<&'static str> {
+    match target.split('-').next()? {
+        "x86_64" => Some("x86_64"),
+        "aarch64" => Some("aarch64"),
+        "i586" | "i686" => Some("i686"),
+        "armv7" => Some("armv7hl"),
+        "riscv64gc" => Some("riscv64"),
+        "powerpc64le" => Some("ppc64le"),
+        _ => None,
+    }
+}
+
+async fn write_rpm(
+    rpmname: &str,
+    files: &[StagedFile],
+    pkg_info: &toml::Value,
+    target: &str,
+) -> Result<(), Error> {
+    println!("Writing RPM package to {rpmname}");
+    let architecture = rpm_architecture(target)
+        .ok_or_else(|| Error::Unsupported(format!("no RPM architecture for {target}")))?;
+    let pkg_name = pkg_info["name"].as_str().unwrap();
+    let pkg_version = pkg_info["version"].as_str().unwrap();
+    let description = pkg_info
+        .get("description")
+        .and_then(|d| d.as_str())
+        .unwrap_or(pkg_name);
+
+    // rpmbuild copies the staged files into its own buildroot
+    let topdir = std::path::absolute(format!("{pkg_name}-{pkg_version}-{target}.rpmbuild"))?;
+    let stagedir = topdir.join("stage");
+    fs::remove_dir_all(&topdir).await.not_found_ok()?;
+    write_dir(&stagedir.join("usr"), files).await?;
+
+    let file_list: String = files
+        .iter()
+        .map(|file| format!("%attr({:o}, root, root) /usr/{}\n", file.mode, file.path))
+        .collect();
+    let spec = format!(
+        RPM_SPEC_FMT!(),
+        pkg_name,
+        // RPM versions can't contain dashes
+        pkg_version.replace('-', "~"),
+        description,
+        pkg_info
+            .get("license")
+            .and_then(|l| l.as_str())
+            .unwrap_or("Unknown"),
+        pkg_info
+            .get("repository")
+            .and_then(|r| r.as_str())
+            .unwrap_or_default(),
+        RPM_REQUIRES,
+        description,
+        stagedir.to_str().unwrap(),
+        file_list,
+    );
+    let specpath = topdir.join("SPECS").join(format!("{pkg_name}.spec"));
+    fs::create_dir_all(specpath.parent().unwrap()).await?;
+    fs::write(&specpath, spec).await?;
+
+    let status = match Command::new("rpmbuild")
+        .arg("-bb")
+        .arg(format!("--target={architecture}"))
+        .arg("--define")
+        .arg(format!("_topdir {}", topdir.to_str().unwrap()))
+        .arg(&specpath)
+        .status()
+        .await
+    {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::MissingTool("rpmbuild", specpath));
+        }
+        result => result?,
+    };
+    if !status.success() {
+        return Err(Error::Tool("rpmbuild", status));
+    }
+
+    let rpmdir = topdir.join("RPMS").join(architecture);
+    let mut rpms = fs::read_dir(&rpmdir).await?;
+    while let Some(rpm) = rpms.next_entry().await? {
+        if rpm.file_name().to_string_lossy().starts_with(pkg_name) {
+            fs::rename(rpm.path(), rpmname).await?;
+            return Ok(());
+        }
+    }
+    Err(Error::NotBuilt(format!(
+        "rpmbuild made no {pkg_name} package in {rpmdir:?}"
+    )))
+}
+
+fn appimage_architecture(target: &str) -> Option<&'static str> {
+    match target.split('-').next()? {
+        "x86_64" => Some("x86_64"),
+        "aarch64" => Some("aarch64"),
+        "i586" | "i686" => Some("i686"),
+        "armv7" => Some("armhf"),
+        _ => None,
+    }
+}
+
+async fn write_appimage(
+    appimagename: &str,
+    files: Vec<StagedFile>,
+    manifest_dir: &Path,
+    pkg_name: &str,
+    target: &str,
+) -> Result<(), Error> {
+    println!("Writing AppImage to {appimagename}");
+    let architecture = appimage_architecture(target)
+        .ok_or_else(|| Error::Unsupported(format!("no AppImage architecture for {target}")))?;
+
+    // the same tree as the other Unix packages under usr, with the entry point,
+    // desktop entry and icon that appimagetool looks for at the top
+    let appdir = PathBuf::from(appimagename.replace(".AppImage", ".AppDir"));
+    let mut appdir_files: Vec<StagedFile> = files
+        .into_iter()
+        .map(|file| StagedFile {
+            path: format!("usr/{}", file.path),
+            ..file
+        })
+        .collect();
+    appdir_files.push(StagedFile::executable(
+        "AppRun".to_string(),
+        format!(APPRUN_FMT!(), pkg_name).into_bytes(),
+    ));
+    appdir_files.push(StagedFile::new(
+        format!("{pkg_name}.desktop"),
+        format!(DESKTOP_ENTRY_FMT!(), pkg_name, pkg_name, pkg_name).into_bytes(),
+    ));
+    appdir_files.push(StagedFile::new(
+        format!("{pkg_name}.svg"),
+        read_file(&manifest_dir.join("icon.svg")).await?,
+    ));
+    write_dir(&appdir, &appdir_files).await?;
+
+    let status = match Command::new("appimagetool")
+        .env("ARCH", architecture)
+        .arg(&appdir)
+        .arg(appimagename)
+        .status()
+        .await
+    {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::MissingTool("appimagetool", appdir));
+        }
+        result => result?,
+    };
+    if !status.success() {
+        return Err(Error::Tool("appimagetool", status));
+    }
+    Ok(())
+}
+
+async fn write_flatpak(
+    dirname: &str,
+    files: Vec<StagedFile>,
+    manifest_dir: &Path,
+    pkg_info: &toml::Value,
+    app_id: &str,
+) -> Result<(), Error> {
+    println!("Writing Flatpak manifest to {dirname}");
+    let pkg_name = pkg_info["name"].as_str().unwrap();
+    // Flatpak only exports desktop entries and icons named after the app ID,
+    // and the executable is built from source by flatpak-builder
+    let mut flatpak_files: Vec<StagedFile> = files
+        .into_iter()
+        .filter(|file| {
+            !file.path.starts_with("share/applications/") && !file.path.starts_with("bin/")
+        })
+        .map(|file| StagedFile {
+            path: file
+                .path
+                .replace(&format!("/apps/{pkg_name}"), &format!("/apps/{app_id}")),
+            ..file
+        })
+        .collect();
+    flatpak_files.push(StagedFile::new(
+        format!("share/applications/{app_id}.desktop"),
+        format!(DESKTOP_ENTRY_FMT!(), pkg_name, app_id, pkg_name).into_bytes(),
+    ));
+    flatpak_files.push(StagedFile::new(
+        format!("share/metainfo/{app_id}.metainfo.xml"),
+        format!(
+            METAINFO_FMT!(),
+            app_id,
+            xml_escape(
+                pkg_info
+                    .get("license")
+                    .and_then(|l| l.as_str())
+                    .unwrap_or("LicenseRef-proprietary")
+            ),
+            xml_escape(pkg_name),
+            xml_escape(
+                pkg_info
+                    .get("description")
+                    .and_then(|d| d.as_str())
+                    .unwrap_or(pkg_name)
+            ),
+            xml_escape(LONG_DESCRIPTION),
+            app_id,
+            xml_escape(pkg_info["version"].as_str().unwrap()),
+        )
+        .into_bytes(),
+    ));
+
+    let dir = Path::new(dirname);
+    write_dir(&dir.join("files"), &flatpak_files).await?;
+    let source = manifest_dir.canonicalize()?;
+    let manifest_path = dir.join(format!("{app_id}.yml"));
+    fs::write(
+        &manifest_path,
+        format!(
+            FLATPAK_MANIFEST_FMT!(),
+            app_id = app_id,
+            name = pkg_name,
+            // single-quoted YAML, where only quotes are escaped, by doubling them
+            source = format!("'{}'", source.to_string_lossy().replace('\'', "''")),
+        ),
+    )
+    .await?;
+
+    let status = match Command::new("flatpak-cargo-generator.py")
+        .arg(manifest_dir.join("Cargo.lock"))
+        .arg("-o")
+        .arg(dir.join("cargo-sources.json"))
+        .status()
+        .await
+    {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::MissingTool(
+                "flatpak-cargo-generator.py",
+                dir.to_path_buf(),
+            ));
+        }
+        result => result?,
+    };
+    if !status.success() {
+        return Err(Error::Tool("flatpak-cargo-generator.py", status));
+    }
+    println!(
+        "Clone https://github.com/flathub/shared-modules into {dirname}, then build with flatpak-builder {manifest_path:?}"
+    );
+    Ok(())
+}
+
+fn wix_architecture(target: &str) -> Option<&'static str> {
+    match target.split('-').next()? {
+        "x86_64" => Some("x64"),
+        "aarch64" => Some("arm64"),
+        "i586" | "i686" => Some("x86"),
+        _ => None,
+    }
+}
+
+async fn write_msi(
+    msiname: &str,
+    files: &[StagedFile],
+    pkg_info: &toml::Value,
+    target: &str,
+) -> Result<(), Error> {
+    println!("Writing Windows installer to {msiname}");
+    let architecture = wix_architecture(target).ok_or_else(|| {
+        Error::Unsupported(format!("no Windows installer architecture for {target}"))
+    })?;
+    let pkg_name = pkg_info["name"].as_str().unwrap();
+    // installer versions are only numbers
+    let version = pkg_info["version"]
+        .as_str()
+        .unwrap()
+        .split(['-', '+'])
+        .next()
+        .unwrap();
+
+    let wixdir = PathBuf::from(msiname.replace(".msi", ".wix"));
+    write_dir(&wixdir, files).await?;
+    let wxsname = format!("{pkg_name}.wxs");
+    fs::write(
+        wixdir.join(&wxsname),
+        format!(
+            WIX_SOURCE_FMT!(),
+            name = xml_escape(pkg_name),
+            manufacturer = xml_escape(&maintainer(pkg_info)),
+            version = version,
+            upgrade_code = WIX_UPGRADE_CODE,
+            exename = xml_escape(&format!("{pkg_name}.exe")),
+            app_user_model_id = xml_escape(APP_USER_MODEL_ID),
+        ),
+    )
+    .await?;
+
+    let status = match Command::new("wix")
+        .current_dir(&wixdir)
+        .arg("build")
+        .arg("-arch")
+        .arg(architecture)
+        .arg("-o")
+        .arg(std::path::absolute(msiname)?)
+        .arg(&wxsname)
+        .status()
+        .await
+    {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::MissingTool("wix", wixdir));
+        }
+        result => result?,
+    };
+    if !status.success() {
+        return Err(Error::Tool("wix", status));
+    }
+    Ok(())
+}
+
+async fn write_dmg(dmgname: &str, files: &[StagedFile], pkg_name: &str) -> Result<(), Error> {
+    println!("Writing disk image to {dmgname}");
+    // the app next to a link to where it should be dragged
+    let dmgroot = PathBuf::from(dmgname.replace(".dmg", ".dmgroot"));
+    write_dir(&dmgroot, files).await?;
+    #[cfg(unix)]
+    fs::symlink("/Applications", dmgroot.join("Applications"))
+        .await
+        .exist_ok()?;
+
+    let status = match Command::new("hdiutil")
+        .arg("create")
+        .arg("-volname")
+        .arg(pkg_name)
+        .arg("-srcfolder")
+        .arg(&dmgroot)
+        .arg("-format")
+        .arg("UDZO")
+        .arg("-ov")
+        .arg(dmgname)
+        .status()
+        .await
+    {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::MissingTool("hdiutil", dmgroot));
+        }
+        result => result?,
+    };
+    if !status.success() {
+        return Err(Error::Tool("hdiutil", status));
+    }
+    Ok(())
+}
+
+// first line of a command's output, if it ran successfully
+async fn command_output(program: &str, args: &[&str], dir: &Path) -> Option<String> {
+    let output = Command::new(program)
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(stdout.lines().next()?.to_string())
+}
+
+fn purl(name: &str, version: &str) -> String {
+    format!("pkg:cargo/{name}@{version}")
+}
+
+// CycloneDX bill of materials for everything in Cargo.lock, which covers
+// the dependencies of every target, not just the one being packaged
+fn cyclonedx_sbom(lock: &toml::Table, pkg_name: &str, pkg_version: &str) -> String {
+    let empty = Vec::new();
+    let packages = lock
+        .get("package")
+        .and_then(|p| p.as_array())
+        .unwrap_or(&empty);
+    let name_version = |package: &toml::Value| {
+        (
+            package["name"].as_str().unwrap_or_default().to_string(),
+            package["version"].as_str().unwrap_or_default().to_string(),
+        )
+    };
+    // dependencies are written as just the name when only one version is locked
+    let resolve = |dependency: &str| {
+        let mut words = dependency.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        match words.next() {
+            Some(version) => purl(name, version),
+            None => packages
+                .iter()
+                .map(name_version)
+                .find(|(n, _)| n == name)
+                .map_or_else(|| purl(name, ""), |(n, v)| purl(&n, &v)),
+        }
+    };
+
+    let mut components = String::new();
+    let mut dependencies = String::new();
+    for package in packages {
+        let (name, version) = name_version(package);
+        let purl = purl(&name, &version);
+        if name != pkg_name || version != pkg_version {
+            let hashes = match package.get("checksum").and_then(|c| c.as_str()) {
+                Some(checksum) => format!(
+                    ", \"hashes\": [{{\"alg\": \"SHA-256\", \"content\": {}}}]",
+                    json_string(checksum)
+                ),
+                None => String::new(),
+            };
+            components += &format!(
+                "\n    {{\"type\": \"library\", \"bom-ref\": {0}, \"name\": {1}, \"version\": {2}, \"purl\": {0}{hashes}}},",
+                json_string(&purl),
+                json_string(&name),
+                json_string(&version),
+            );
+        }
+        let depends_on: Vec<String> = package
+            .get("dependencies")
+            .and_then(|d| d.as_array())
+            .unwrap_or(&empty)
+            .iter()
+            .filter_map(|d| d.as_str())
+            .map(|d| json_string(&resolve(d)))
+            .collect();
+        dependencies += &format!(
+            "\n    {{\"ref\": {}, \"dependsOn\": [{}]}},",
+            json_string(&purl),
+            depends_on.join(", ")
+        );
+    }
+
+    let purl = purl(pkg_name, pkg_version);
+    format!(
+        CYCLONEDX_FMT!(),
+        format!(
+            "{{\"type\": \"application\", \"bom-ref\": {0}, \"name\": {1}, \"version\": {2}, \"purl\": {0}}}",
+            json_string(&purl),
+            json_string(pkg_name),
+            json_string(pkg_version),
+        ),
+        components.trim_end_matches(','),
+        dependencies.trim_end_matches(','),
+    )
+}
+
+async fn sha256_file(path: &str) -> io::Result<String> {
+    Ok(format!(
+        "{:x}",
+        Sha256::digest(read_file(Path::new(path)).await?)
+    ))
+}
+
+// build manifest, SBOM, and checksums of those and the package itself
+async fn write_release_metadata(
+    basename: &str,
+    package: &str,
+    manifest: &toml::Table,
+    manifest_dir: &Path,
+    target: &str,
+    format: &str,
+    build: &BuildOptions,
+) -> Result<(), Error> {
+    let pkg_info = &manifest["package"];
+    let pkg_name = pkg_info["name"].as_str().unwrap();
+    let pkg_version = pkg_info["version"].as_str().unwrap();
+
+    let mut features = build.features.clone();
+    if !build
+        .cargo_args
+        .iter()
+        .any(|arg| arg == "--no-default-features")
+    {
+        if let Some(default) = manifest
+            .get("features")
+            .and_then(|f| f.get("default"))
+            .and_then(|d| d.as_array())
+        {
+            features.extend(default.iter().filter_map(|f| f.as_str()).map(str::to_owned));
+        }
+    }
+    features.sort();
+    features.dedup();
+    let features: Vec<String> = features.iter().map(|f| json_string(f)).collect();
+    let optional_string = |s: Option<String>| s.as_deref().map_or("null".to_string(), json_string);
+    let build_manifest = format!(
+        BUILD_MANIFEST_FMT!(),
+        json_string(pkg_name),
+        json_string(pkg_version),
+        json_string(target),
+        json_string(format),
+        optional_string(command_output("git", &["rev-parse", "HEAD"], manifest_dir).await),
+        optional_string(command_output("rustc", &["-V"], manifest_dir).await),
+        json_string(&build.builder),
+        json_string(&build.profile),
+        features.join(", "),
+    );
+    let build_manifest_name = format!("{basename}.build.json");
+    println!("Writing build manifest to {build_manifest_name}");
+    fs::write(&build_manifest_name, &build_manifest).await?;
+
+    // Cargo.lock was just written by the build
+    let lock = String::from_utf8_lossy(&read_file(&manifest_dir.join("Cargo.lock")).await?)
+        .parse::<toml::Table>()?;
+    let sbom = cyclonedx_sbom(&lock, pkg_name, pkg_version);
+    let sbom_name = format!("{basename}.cdx.json");
+    println!("Writing bill of materials to {sbom_name}");
+    fs::write(&sbom_name, &sbom).await?;
+
+    // in the format of sha256sum, so it can check them
+    let mut checksums = String::new();
+    // a Flatpak is a directory to build from, not something to check
+    if fs::metadata(package).await?.is_file() {
+        checksums += &format!("{}  {package}\n", sha256_file(package).await?);
+    }
+    for (name, data) in [(&build_manifest_name, build_manifest), (&sbom_name, sbom)] {
+        checksums += &format!("{:x}  {name}\n", Sha256::digest(data));
+    }
+    let checksums_name = format!("{basename}.sha256");
+    println!("Writing checksums to {checksums_name}");
+    fs::write(&checksums_name, checksums).await?;
+    Ok(())
+}
+
+async fn cargo_build(
+    manifest_path: &Path,
+    target: &str,
+    target_dir: &Path,
+    build: &BuildOptions,
+) -> Result<(), Error> {
+    // cross and zigbuild take the same arguments as cargo build
+    let mut command = match build.builder.as_str() {
+        "cross" => Command::new("cross"),
+        _ => Command::new("cargo"),
+    };
+    command
+        .arg(if build.builder == "zigbuild" {
+            "zigbuild"
+        } else {
+            "build"
+        })
+        .arg(format!("--profile={}", build.profile))
+        .arg(format!(
+            "--manifest-path={}",
+            &manifest_path.to_str().unwrap()
+        ))
+        .arg(format!("--target={target}"))
+        .arg(format!("--target-dir={}", target_dir.to_str().unwrap()));
+    if !build.features.is_empty() {
+        command.arg(format!("--features={}", build.features.join(",")));
+    }
+    command.args(&build.cargo_args);
+    let status = command.spawn()?.wait().await?;
+    if !status.success() {
+        return Err(Error::Build(status));
+    }
+    Ok(())
+}
+
+// what every kind of package is made from, besides the files
+struct Release<'a> {
+    manifest_dir: &'a Path,
+    pkg_info: &'a toml::Value,
+    app_id: &'a str,
+    target: &'a str,
+    mtime: u64,
+}
+
+async fn write_package(
+    release: &Release<'_>,
+    format: &str,
+    package: &str,
+    files: Vec<StagedFile>,
+) -> Result<(), Error> {
+    let Release {
+        manifest_dir,
+        pkg_info,
+        app_id,
+        target,
+        mtime,
+    } = *release;
+    let pkg_name = pkg_info["name"].as_str().unwrap();
+    match format {
+        "deb" => write_deb(package, &files, pkg_info, target, mtime)?,
+        "rpm" => write_rpm(package, &files, pkg_info, target).await?,
+        "appimage" => write_appimage(package, files, manifest_dir, pkg_name, target).await?,
+        "flatpak" => write_flatpak(package, files, manifest_dir, pkg_info, app_id).await?,
+        "dmg" => write_dmg(package, &files, pkg_name).await?,
+        "msi" => write_msi(package, &files, pkg_info, target).await?,
+        _ => write_zip(package, &files, mtime).await?,
+    }
+    Ok(())
+}
+
+// everything that's the same for each target
+struct Job {
+    manifest_dir: PathBuf,
+    manifest_path: PathBuf,
+    manifest: toml::Table,
+    format: String,
+    app_id: String,
+    build: BuildOptions,
+    verify: bool,
+    dbus_activation: bool,
+}
+
+// where the executable ends up once the package is installed, for files that
+// need its full path; zips are meant to be copied into /usr/local
+fn installed_bin_dir(format: &str) -> &'static str {
+    match format {
+        "deb" | "rpm" => "/usr/bin",
+        "flatpak" => "/app/bin",
+        _ => "/usr/local/bin",
+    }
+}
+
+// build and package for one target, giving the name of the package
+async fn package_target(job: &Job, target: &str) -> Result<String, Error> {
+    println!("Packaging for target triple {target}");
+
+    // ensure a valid target triple
+    let _ = target_spec::Triple::from_str(target)?;
+
+    let Job {
+        manifest_dir,
+        manifest_path,
+        manifest,
+        format,
+        app_id,
+        build,
+        verify,
+        dbus_activation,
+    } = job;
+    let manifest_dir = manifest_dir.as_path();
+    let format = format.as_str();
+    let is_macos = target_spec::eval("cfg(target_os = \"macos\")", target)?.unwrap();
+    let is_windows = target_spec::eval("cfg(windows)", target)?.unwrap();
+    let is_unix = target_spec::eval("cfg(unix)", target)?.unwrap();
+    let is_linux = target_spec::eval("cfg(target_os = \"linux\")", target)?.unwrap();
+    if (format == "deb" || format == "rpm") && (is_macos || !is_unix) {
+        return Err(Error::Unsupported(format!(
+            "{format} packages can't be made for {target}"
+        )));
+    }
+    if (format == "appimage" || format == "flatpak") && !is_linux {
+        return Err(Error::Unsupported(format!(
+            "{format} packages can't be made for {target}"
+        )));
+    }
+    if (format == "dmg" && !is_macos) || (format == "msi" && !is_windows) {
+        return Err(Error::Unsupported(format!(
+            "{format} packages can't be made for {target}"
+        )));
+    }
+    // rpmbuild, appimagetool, wix and hdiutil put build times, hosts or
+    // random IDs in what they make, and a Flatpak isn't built here at all
+    if *verify && format != "zip" && format != "deb" {
+        return Err(Error::Unsupported(format!(
+            "--verify only works for zip and deb packages, {format} ones aren't reproducible"
+        )));
+    }
+
+    let pkg_info = &manifest["package"];
+    let pkg_name = pkg_info["name"].as_str().unwrap();
+    let pkg_version = pkg_info["version"]
+        .as_str()
+        .expect("Package has no version");
+
+    let dbus_activation = dbus_activation.then(|| installed_bin_dir(format));
+    let epoch = source_date_epoch(manifest_dir).await;
+    // packaging twice at the current time would only prove the clock moved
+    if *verify && epoch.is_none() {
+        return Err(Error::Unsupported(
+            "--verify needs SOURCE_DATE_EPOCH set, or a git commit to take the time from"
+                .to_string(),
+        ));
+    }
+
+    println!("Building {pkg_name} version {pkg_version} for {target}");
+    let target_dir = manifest_dir.join("target");
+    cargo_build(manifest_path, target, &target_dir, build).await?;
+    let release = Release {
+        manifest_dir,
+        pkg_info,
+        app_id,
+        target,
+        mtime: epoch.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        }),
+    };
+    let files = stage(
+        &release,
+        &target_dir.join(target).join(build.profile_dir()),
+        dbus_activation,
+    )
+    .await?;
+
+    if is_macos {
+        verify_plist(ENTITLEMENTS.as_bytes(), &[])?;
+        let entitlements = format!("{pkg_name}.entitlements");
+        fs::write(&entitlements, ENTITLEMENTS).await?;
+        println!(
+            "To notarize, sign {pkg_name}.app with codesign --options runtime --entitlements {entitlements}"
+        );
+    }
+
+    let basename = format!("{pkg_name}-{pkg_version}-{target}");
+    let package = match format {
+        "deb" => format!(
+            "{pkg_name}_{pkg_version}_{}.deb",
+            deb_architecture(target).unwrap_or(target)
+        ),
+        "rpm" => format!(
+            "{pkg_name}-{pkg_version}-1.{}.rpm",
+            rpm_architecture(target).unwrap_or(target)
+        ),
+        "appimage" => format!(
+            "{pkg_name}-{pkg_version}-{}.AppImage",
+            appimage_architecture(target).unwrap_or(target)
+        ),
+        _ => format!("{basename}.{format}"),
+    };
+    write_package(&release, format, &package, files).await?;
+    // so the summary never lists a package that isn't there
+    if fs::metadata(&package).await.is_err() {
+        return Err(Error::NotBuilt(format!("{package} was not made")));
+    }
+
+    write_release_metadata(
+        &basename,
+        &package,
+        manifest,
+        manifest_dir,
+        target,
+        format,
+        build,
+    )
+    .await?;
+
+    if *verify {
+        println!("Building {pkg_name} again to verify {package}");
+        let verify_dir = format!("{basename}.verify");
+        fs::create_dir_all(&verify_dir).await?;
+        let target_dir = target_dir.join("verify");
+        cargo_build(manifest_path, target, &target_dir, build).await?;
+        let files = stage(
+            &release,
+            &target_dir.join(target).join(build.profile_dir()),
+            dbus_activation,
+        )
+        .await?;
+        let rebuilt = format!("{verify_dir}/{package}");
+        write_package(&release, format, &rebuilt, files).await?;
+
+        let hash = sha256_file(&package).await?;
+        let rebuilt_hash = sha256_file(&rebuilt).await?;
+        if hash != rebuilt_hash {
+            return Err(Error::Verify(format!(
+                "{package} is not reproducible, it has SHA-256 {hash} but {rebuilt} has {rebuilt_hash}"
+            )));
+        }
+        println!("Verified {package} with SHA-256 {hash}");
+    }
+    Ok(package)
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn main() -> std::process::ExitCode {
+    match run().await {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run() -> Result<(), Error> {
     let matches = command!()
         .arg(
             clap::Arg::new("manifest-path")
@@ -90,7 +1727,46 @@ async fn main() -> Result<(), Error> {
                 .required(false)
                 .value_parser(value_parser!(PathBuf)),
         )
-        .arg(arg!(--target <TRIPLE>).required(false))
+        .arg(
+            arg!(--target <TRIPLE> "Target to package for, can be given more than once")
+                .required(false)
+                .action(clap::ArgAction::Append),
+        )
+        .arg(arg!(--all "Package for every target listed in [package.metadata.package]"))
+        .arg(
+            arg!(--format <FORMAT> "Kind of package to produce")
+                .required(false)
+                .value_parser(["zip", "deb", "rpm", "appimage", "flatpak", "dmg", "msi"])
+                .default_value("zip"),
+        )
+        .arg(
+            arg!(--"bundle-id" <ID> "Reverse-DNS identifier for macOS bundles and Flatpaks")
+                .required(false),
+        )
+        .arg(arg!(--verify "Build again from scratch, and check the zip or deb package comes out the same"))
+        .arg(arg!(--"dbus-activation" "Let D-Bus start the Linux service on demand"))
+        .arg(
+            arg!(--features <FEATURES> "Space or comma separated list of features to build with")
+                .required(false),
+        )
+        .arg(
+            arg!(--profile <PROFILE> "Cargo profile to build with")
+                .required(false)
+                .default_value("release"),
+        )
+        .arg(
+            arg!(--builder <BUILDER> "What to build with, cross or zigbuild can help with foreign targets")
+                .required(false)
+                .value_parser(["cargo", "cross", "zigbuild"])
+                .default_value("cargo"),
+        )
+        .arg(
+            clap::Arg::new("cargo-args")
+                .value_name("CARGO_ARGS")
+                .help("Passed on to cargo build")
+                .num_args(0..)
+                .last(true),
+        )
         .get_matches();
 
     let manifest_path;
@@ -105,15 +1781,6 @@ async fn main() -> Result<(), Error> {
     };
     println!("Using manifest at {:?}", &manifest_path);
 
-    let target = matches
-        .get_one::<String>("target")
-        .map(|v| v.as_str())
-        .unwrap_or(env!("TARGET"));
-    println!("Using target triple {target}");
-
-    // ensure a valid target triple
-    let _ = target_spec::Triple::from_str(target)?;
-
     // parse out project toml
     let mut manifile = fs::File::open(&manifest_path).await?;
     let mut manidata = String::new();
@@ -121,152 +1788,108 @@ async fn main() -> Result<(), Error> {
     let manifest = manidata.parse::<toml::Table>()?;
     let pkg_info = &manifest["package"];
     let pkg_name = pkg_info["name"].as_str().expect("Package has no name");
-    let pkg_version = pkg_info["version"]
-        .as_str()
-        .expect("Package has no version");
-
-    println!("Building {pkg_name} version {pkg_version}");
-    Command::new("cargo")
-        .arg("build")
-        .arg("--release")
-        .arg(format!(
-            "--manifest-path={}",
-            &manifest_path.to_str().unwrap()
-        ))
-        .arg(format!("--target={target}"))
-        .spawn()?
-        .wait()
-        .await?;
-
-    let zipname = format!("{pkg_name}-{pkg_version}-{target}.zip");
-    println!("Zipping to {zipname}");
-    let mut zipfile = fs::File::create(zipname).await?;
-    let mut zipwriter = ZipFileWriter::with_tokio(&mut zipfile);
-
-    if target_spec::eval("cfg(target_os = \"macos\")", target)?.unwrap() {
-        let mut exefile = fs::File::open(
-            manifest_dir
-                .join("target")
-                .join(target)
-                .join("release")
-                .join(pkg_name),
-        )
-        .await?;
-        let mut exedata = Vec::new();
-        exefile.read_to_end(&mut exedata).await?;
-
-        let exeentry = ZipEntryBuilder::new(
-            format!("{pkg_name}.app/Contents/MacOS/{pkg_name}").into(),
-            Compression::Deflate,
-        )
-        .attribute_compatibility(AttributeCompatibility::Unix)
-        .unix_permissions(0o755)
-        .build();
-        zipwriter.write_entry_whole(exeentry, &exedata).await?;
-
-        let mut icon = icns::Icns::new();
-        icon.add_entries(
-            iconwriter::resample::linear,
-            &Image::open(manifest_dir.join("icon.svg"))?,
-            vec![
-                icns::Key::Rgba16,
-                icns::Key::Rgba32,
-                icns::Key::Rgba64,
-                icns::Key::Rgba128,
-                icns::Key::Rgba256,
-                icns::Key::Rgba512,
-                icns::Key::Rgba1024,
-            ],
-        )?;
-        let mut icondata = Vec::new();
-        icon.write(&mut icondata)?;
-
-        let iconentry = ZipEntryBuilder::new(
-            format!("{pkg_name}.app/Contents/Resources/Icon.icns").into(),
-            Compression::Deflate,
-        )
-        .build();
-        zipwriter.write_entry_whole(iconentry, &icondata).await?;
-
-        let infoentry = ZipEntryBuilder::new(
-            format!("{pkg_name}.app/Contents/Info.plist").into(),
-            Compression::Deflate,
-        )
-        .build();
-        zipwriter
-            .write_entry_whole(
-                infoentry,
-                format!(INFO_PLIST_FMT!(), pkg_name, pkg_name, pkg_version).as_bytes(),
-            )
-            .await?;
-    } else if target_spec::eval("cfg(windows)", target)?.unwrap() {
-        let exename = format!("{pkg_name}.exe");
-        let mut exefile = fs::File::open(
-            manifest_dir
-                .join("target")
-                .join(target)
-                .join("release")
-                .join(&exename),
-        )
-        .await?;
-        let mut exedata = Vec::new();
-        exefile.read_to_end(&mut exedata).await?;
-
-        let exeentry = ZipEntryBuilder::new(exename.into(), Compression::Deflate).build();
-        zipwriter.write_entry_whole(exeentry, &exedata).await?;
-    } else if target_spec::eval("cfg(unix)", target)?.unwrap() {
-        let mut exefile = fs::File::open(
-            manifest_dir
-                .join("target")
-                .join(target)
-                .join("release")
-                .join(pkg_name),
-        )
-        .await?;
-        let mut exedata = Vec::new();
-        exefile.read_to_end(&mut exedata).await?;
-
-        let exeentry = ZipEntryBuilder::new(format!("bin/{pkg_name}").into(), Compression::Deflate)
-            .attribute_compatibility(AttributeCompatibility::Unix)
-            .unix_permissions(0o755)
-            .build();
-        zipwriter.write_entry_whole(exeentry, &exedata).await?;
-
-        let mut iconfile = fs::File::open(manifest_dir.join("icon.svg")).await?;
-        let mut icondata = Vec::new();
-        iconfile.read_to_end(&mut icondata).await?;
+    let app_id = matches
+        .get_one::<String>("bundle-id")
+        .cloned()
+        .unwrap_or_else(|| default_app_id(pkg_name));
+    if !valid_app_id(&app_id) {
+        return Err(Error::Usage(format!(
+            "{app_id:?} is not a valid bundle identifier"
+        )));
+    }
 
-        let iconentry = ZipEntryBuilder::new(
-            format!("share/icons/hicolor/scalable/apps/{pkg_name}.svg").into(),
-            Compression::Deflate,
-        )
-        .build();
-        zipwriter.write_entry_whole(iconentry, &icondata).await?;
+    let mut targets: Vec<String> = matches
+        .get_many::<String>("target")
+        .map(|targets| targets.cloned().collect())
+        .unwrap_or_default();
+    if matches.get_flag("all") {
+        let all = pkg_info
+            .get("metadata")
+            .and_then(|m| m.get("package"))
+            .and_then(|p| p.get("targets"))
+            .and_then(|t| t.as_array())
+            .ok_or_else(|| {
+                Error::Usage(
+                    "--all needs a targets list under [package.metadata.package]".to_string(),
+                )
+            })?;
+        targets.extend(all.iter().filter_map(|t| t.as_str()).map(str::to_owned));
+    }
+    if targets.is_empty() {
+        targets.push(env!("TARGET").to_string());
+    }
+    let mut seen = std::collections::HashSet::new();
+    targets.retain(|target| seen.insert(target.clone()));
 
-        let iconentry = ZipEntryBuilder::new(
-            format!("share/icons/hicolor/symbolic/apps/{pkg_name}-symbolic.svg").into(),
-            Compression::Deflate,
-        )
-        .build();
-        zipwriter.write_entry_whole(iconentry, &icondata).await?;
+    let build = BuildOptions {
+        builder: matches.get_one::<String>("builder").unwrap().clone(),
+        profile: matches.get_one::<String>("profile").unwrap().clone(),
+        features: matches
+            .get_one::<String>("features")
+            .map(|features| {
+                features
+                    .split([' ', ','])
+                    .filter(|feature| !feature.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        cargo_args: matches
+            .get_many::<String>("cargo-args")
+            .map(|args| args.cloned().collect())
+            .unwrap_or_default(),
+    };
+    let job = std::sync::Arc::new(Job {
+        manifest_dir: manifest_dir.to_path_buf(),
+        manifest_path,
+        manifest,
+        format: matches.get_one::<String>("format").unwrap().clone(),
+        app_id,
+        build,
+        verify: matches.get_flag("verify"),
+        dbus_activation: matches.get_flag("dbus-activation"),
+    });
 
-        let desktopentry = ZipEntryBuilder::new(
-            format!("share/applications/{pkg_name}.desktop").into(),
-            Compression::Deflate,
-        )
-        .build();
-        zipwriter
-            .write_entry_whole(
-                desktopentry,
-                format!(
-                    DESKTOP_ENTRY_FMT!(),
-                    pkg_name, pkg_name, pkg_name
-                )
-                .as_bytes(),
-            )
-            .await?;
+    // cargo takes turns building in the shared target directory,
+    // but everything else for each target happens at the same time
+    let mut tasks = tokio::task::JoinSet::new();
+    for target in &targets {
+        let job = job.clone();
+        let target = target.clone();
+        tasks.spawn(async move {
+            let result = package_target(&job, &target).await;
+            (target, result)
+        });
+    }
+    let mut results = std::collections::HashMap::new();
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((target, result)) => {
+                results.insert(target, result);
+            }
+            Err(e) => eprintln!("error: packaging task failed: {e}"),
+        }
     }
 
-    zipwriter.close().await?;
+    let width = targets.iter().map(String::len).max().unwrap_or(0);
+    let mut failed = 0;
+    println!();
+    println!("{:width$}  RESULT  PACKAGE", "TARGET");
+    for target in &targets {
+        match results.get(target) {
+            Some(Ok(package)) => println!("{target:width$}  ok      {package}"),
+            Some(Err(e)) => {
+                failed += 1;
+                println!("{target:width$}  failed  {e}");
+            }
+            None => {
+                failed += 1;
+                println!("{target:width$}  failed  (task panicked)");
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(Error::Failed(failed, targets.len()));
+    }
     Ok(())
 }
@@ -0,0 +1,698 @@
+// Pairing a new device through BlueZ, for the tray's "Pair new device…" item.
+// The adapter is made pairable and discoverable for a while, and an agent asks
+// on the desktop to confirm the number the device shows. Everything goes
+// through the connections and bus name it's given, so it works the same
+// against a stand-in for BlueZ.
+
+use crate::actions::{self, DesktopEvent};
+use crate::bluez::{self, BLUEZ};
+use futures::stream::StreamExt;
+use notify_rust::Notification;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue};
+
+// object paths can't have dashes, so this isn't from CARGO_PKG_NAME
+const AGENT_PATH: &str = "/net/boatcake/ios_notif_forward/agent";
+// how long the adapter stays discoverable
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, zbus::DBusError)]
+#[zbus(prefix = "org.bluez.Error")]
+enum AgentError {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    Rejected(String),
+    Canceled(String),
+}
+
+async fn proxy<'a>(
+    connection: &zbus::Connection,
+    destination: &'a str,
+    path: &'a str,
+    interface: &'a str,
+) -> zbus::Result<zbus::Proxy<'a>> {
+    zbus::proxy::Builder::new(connection)
+        .destination(destination)?
+        .path(path)?
+        .interface(interface)?
+        .cache_properties(zbus::proxy::CacheProperties::No)
+        .build()
+        .await
+}
+
+async fn device_alias(system: &zbus::Connection, destination: &str, device: &str) -> String {
+    let alias = match proxy(system, destination, device, "org.bluez.Device1").await {
+        Ok(device) => device.get_property::<String>("Alias").await.ok(),
+        Err(_) => None,
+    };
+    alias.unwrap_or_else(|| device.rsplit('/').next().unwrap_or(device).to_string())
+}
+
+// the desktop's notification server, on the session connection that's given
+async fn notifications(session: &zbus::Connection) -> zbus::Result<zbus::Proxy<'static>> {
+    proxy(
+        session,
+        "org.freedesktop.Notifications",
+        "/org/freedesktop/Notifications",
+        "org.freedesktop.Notifications",
+    )
+    .await
+}
+
+struct Agent {
+    system: zbus::Connection,
+    session: zbus::Connection,
+    destination: String,
+    // the desktop notification asking to confirm, so Cancel can close it
+    prompt: Mutex<Option<u32>>,
+}
+
+impl Agent {
+    // shows a notification with Pair and Reject actions, and waits for one
+    async fn ask(&self, summary: &str, body: &str) -> Result<(), AgentError> {
+        let rule = zbus::MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .interface("org.freedesktop.Notifications")?
+            .build();
+        // listening before showing it, so an answer can't be missed
+        let mut signals = zbus::MessageStream::for_match_rule(rule, &self.session, None).await?;
+        // on the same connection as the signals, rather than through notify-rust's own
+        let notif_id = notifications(&self.session)
+            .await?
+            .call::<_, _, u32>(
+                "Notify",
+                &(
+                    env!("CARGO_PKG_NAME"),
+                    0u32,
+                    "",
+                    summary,
+                    body,
+                    ["pair", "Pair", "reject", "Reject"].as_slice(),
+                    HashMap::<&str, zbus::zvariant::Value>::new(),
+                    // never expires
+                    0i32,
+                ),
+            )
+            .await?;
+        *self.prompt.lock().unwrap() = Some(notif_id);
+        let mut accepted = false;
+        while let Some(signal) = signals.next().await {
+            match signal
+                .ok()
+                .and_then(|signal| actions::parse_signal(&signal))
+            {
+                Some(DesktopEvent::Action(id, action)) if id == notif_id => {
+                    accepted = action == "pair";
+                    break;
+                }
+                Some(DesktopEvent::Closed(id, _)) if id == notif_id => break,
+                _ => {}
+            }
+        }
+        *self.prompt.lock().unwrap() = None;
+        if accepted {
+            Ok(())
+        } else {
+            Err(AgentError::Rejected("declined on the desktop".to_string()))
+        }
+    }
+}
+
+#[zbus::interface(name = "org.bluez.Agent1")]
+impl Agent {
+    async fn release(&self) {}
+
+    // only for devices without a display, which an iPhone isn't
+    async fn request_pin_code(&self, _device: ObjectPath<'_>) -> Result<String, AgentError> {
+        Err(AgentError::Rejected(
+            "PIN codes aren't supported".to_string(),
+        ))
+    }
+
+    async fn display_pin_code(&self, device: ObjectPath<'_>, pincode: String) {
+        let alias = device_alias(&self.system, &self.destination, &device).await;
+        if let Err(e) = Notification::new()
+            .summary(&format!("Pairing with {alias}"))
+            .body(&format!("Enter {pincode} on the device"))
+            .show()
+        {
+            eprintln!("error showing PIN code: {e:?}");
+        }
+    }
+
+    async fn request_passkey(&self, _device: ObjectPath<'_>) -> Result<u32, AgentError> {
+        Err(AgentError::Rejected(
+            "passkeys aren't supported".to_string(),
+        ))
+    }
+
+    async fn display_passkey(&self, device: ObjectPath<'_>, passkey: u32, entered: u16) {
+        // called again for every digit typed, which isn't worth a notification each
+        if entered == 0 {
+            self.display_pin_code(device, format!("{passkey:06}")).await;
+        }
+    }
+
+    async fn request_confirmation(
+        &self,
+        device: ObjectPath<'_>,
+        passkey: u32,
+    ) -> Result<(), AgentError> {
+        let alias = device_alias(&self.system, &self.destination, &device).await;
+        self.ask(
+            &format!("Pair with {alias}?"),
+            &format!("Check that the device shows {passkey:06}"),
+        )
+        .await
+    }
+
+    async fn request_authorization(&self, device: ObjectPath<'_>) -> Result<(), AgentError> {
+        let alias = device_alias(&self.system, &self.destination, &device).await;
+        self.ask(&format!("Pair with {alias}?"), "").await
+    }
+
+    async fn authorize_service(
+        &self,
+        _device: ObjectPath<'_>,
+        _uuid: String,
+    ) -> Result<(), AgentError> {
+        Ok(())
+    }
+
+    async fn cancel(&self) -> Result<(), AgentError> {
+        let notif_id = *self.prompt.lock().unwrap();
+        if let Some(notif_id) = notif_id {
+            // the prompt's wait ends when it's closed
+            notifications(&self.session)
+                .await?
+                .call::<_, _, ()>("CloseNotification", &(notif_id,))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+// the adapter with this name, e.g. hci0, or else the first one
+async fn find_adapter(
+    system: &zbus::Connection,
+    destination: &str,
+    name: Option<&str>,
+) -> zbus::Result<OwnedObjectPath> {
+    let objects = bluez::managed_objects(system, destination).await?;
+    objects
+        .into_iter()
+        .filter(|(_, interfaces)| {
+            interfaces
+                .keys()
+                .any(|interface| interface.as_str() == "org.bluez.Adapter1")
+        })
+        .map(|(path, _)| path)
+        .filter(|path| name.is_none_or(|name| path.as_str().rsplit('/').next() == Some(name)))
+        .min_by(|a, b| a.as_str().cmp(b.as_str()))
+        .ok_or_else(|| zbus::Error::Failure("no Bluetooth adapter found".to_string()))
+}
+
+fn is_paired(properties: &HashMap<String, OwnedValue>) -> bool {
+    properties
+        .get("Paired")
+        .is_some_and(|paired| paired.downcast_ref::<bool>() == Ok(true))
+}
+
+// waits for a device on the adapter to become paired, and returns its path
+async fn wait_for_paired(
+    system: &zbus::Connection,
+    adapter: &str,
+) -> zbus::Result<Option<OwnedObjectPath>> {
+    let changed_rule = zbus::MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .path_namespace(adapter)?
+        .arg(0, "org.bluez.Device1")?
+        .build();
+    let mut changes = zbus::MessageStream::for_match_rule(changed_rule, system, None).await?;
+    // devices that are new to BlueZ can already be paired when they show up
+    let added_rule = zbus::MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .interface("org.freedesktop.DBus.ObjectManager")?
+        .member("InterfacesAdded")?
+        .build();
+    let mut additions = zbus::MessageStream::for_match_rule(added_rule, system, None).await?;
+    let device_prefix = format!("{adapter}/");
+    let timeout = tokio::time::sleep(PAIRING_TIMEOUT);
+    tokio::pin!(timeout);
+    loop {
+        tokio::select! {
+            _ = &mut timeout => return Ok(None),
+            signal = changes.next() => {
+                let Some(signal) = signal else {
+                    return Ok(None);
+                };
+                let signal = signal?;
+                let Ok((_, changed, _)) = signal
+                    .body()
+                    .deserialize::<(String, HashMap<String, OwnedValue>, Vec<String>)>()
+                else {
+                    continue;
+                };
+                if let (true, Some(path)) = (is_paired(&changed), signal.header().path()) {
+                    return Ok(Some(path.to_owned().into()));
+                }
+            },
+            signal = additions.next() => {
+                let Some(signal) = signal else {
+                    return Ok(None);
+                };
+                let Ok((path, interfaces)) = signal?
+                    .body()
+                    .deserialize::<(OwnedObjectPath, HashMap<String, HashMap<String, OwnedValue>>)>()
+                else {
+                    continue;
+                };
+                if path.as_str().starts_with(&device_prefix)
+                    && interfaces.get("org.bluez.Device1").is_some_and(is_paired)
+                {
+                    return Ok(Some(path));
+                }
+            },
+        }
+    }
+}
+
+// Returns the name of the device that was paired, or None if nothing was in time.
+pub async fn pair(
+    system: &zbus::Connection,
+    session: &zbus::Connection,
+    destination: &str,
+    adapter_name: Option<&str>,
+) -> zbus::Result<Option<String>> {
+    let adapter_path = find_adapter(system, destination, adapter_name).await?;
+    let adapter = proxy(system, destination, &adapter_path, "org.bluez.Adapter1").await?;
+    let agent_manager = proxy(system, destination, "/org/bluez", "org.bluez.AgentManager1").await?;
+
+    let agent = Agent {
+        system: system.clone(),
+        session: session.clone(),
+        destination: destination.to_string(),
+        prompt: Mutex::new(None),
+    };
+    system.object_server().at(AGENT_PATH, agent).await?;
+    let agent_path = ObjectPath::from_static_str_unchecked(AGENT_PATH);
+    let result = async {
+        agent_manager
+            .call::<_, _, ()>("RegisterAgent", &(&agent_path, "DisplayYesNo"))
+            .await?;
+        agent_manager
+            .call::<_, _, ()>("RequestDefaultAgent", &(&agent_path,))
+            .await?;
+
+        // put back however pairing goes
+        let was_pairable = adapter.get_property::<bool>("Pairable").await?;
+        let was_discoverable = adapter.get_property::<bool>("Discoverable").await?;
+        let old_timeout = adapter.get_property::<u32>("DiscoverableTimeout").await?;
+        let paired = async {
+            adapter.set_property("Pairable", true).await?;
+            adapter
+                .set_property("DiscoverableTimeout", PAIRING_TIMEOUT.as_secs() as u32)
+                .await?;
+            adapter.set_property("Discoverable", true).await?;
+
+            let adapter_alias = adapter
+                .get_property::<String>("Alias")
+                .await
+                .unwrap_or_default();
+            if let Err(e) = Notification::new()
+                .summary("Pairing a new device")
+                .body(&format!(
+                    "On the device, choose {adapter_alias} in Bluetooth settings"
+                ))
+                .show()
+            {
+                eprintln!("error showing pairing instructions: {e:?}");
+            }
+            wait_for_paired(system, adapter_path.as_str()).await
+        }
+        .await;
+
+        if let Err(e) = adapter.set_property("Discoverable", was_discoverable).await {
+            eprintln!("error restoring the adapter's discoverable setting: {e:?}");
+        }
+        if let Err(e) = adapter
+            .set_property("DiscoverableTimeout", old_timeout)
+            .await
+        {
+            eprintln!("error restoring the adapter's discoverable timeout: {e:?}");
+        }
+        if let Err(e) = adapter.set_property("Pairable", was_pairable).await {
+            eprintln!("error restoring the adapter's pairable setting: {e:?}");
+        }
+
+        let Some(device_path) = paired? else {
+            return Ok(None);
+        };
+        // so it can connect again later without asking
+        proxy(system, destination, &device_path, "org.bluez.Device1")
+            .await?
+            .set_property("Trusted", true)
+            .await?;
+        Ok(Some(device_alias(system, destination, &device_path).await))
+    }
+    .await;
+
+    if let Err(e) = agent_manager
+        .call::<_, _, ()>("UnregisterAgent", &(&agent_path,))
+        .await
+    {
+        eprintln!("error unregistering pairing agent: {e:?}");
+    }
+    system
+        .object_server()
+        .remove::<Agent, _>(AGENT_PATH)
+        .await?;
+    result
+}
+
+// what the tray item runs, on the real buses, telling the user how it went
+pub async fn pair_new_device(adapter_name: Option<String>) {
+    let result = async {
+        let system = zbus::Connection::system().await?;
+        let session = zbus::Connection::session().await?;
+        pair(&system, &session, BLUEZ, adapter_name.as_deref()).await
+    }
+    .await;
+    let (summary, body) = match result {
+        Ok(Some(alias)) => (
+            format!("Paired with {alias}"),
+            "Its notifications will show here once it's connected".to_string(),
+        ),
+        Ok(None) => (
+            "Nothing was paired".to_string(),
+            "No device paired in time".to_string(),
+        ),
+        Err(e) => {
+            eprintln!("error pairing: {e:?}");
+            ("Couldn't pair".to_string(), e.to_string())
+        }
+    };
+    if let Err(e) = Notification::new().summary(&summary).body(&body).show() {
+        eprintln!("error showing pairing result: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Adapter {
+        pairable: bool,
+        discoverable: bool,
+        discoverable_timeout: u32,
+    }
+
+    #[zbus::interface(name = "org.bluez.Adapter1")]
+    impl Adapter {
+        #[zbus(property)]
+        fn alias(&self) -> String {
+            "Test computer".to_string()
+        }
+
+        #[zbus(property)]
+        fn pairable(&self) -> bool {
+            self.pairable
+        }
+
+        #[zbus(property)]
+        fn set_pairable(&mut self, pairable: bool) {
+            self.pairable = pairable;
+        }
+
+        #[zbus(property)]
+        fn discoverable(&self) -> bool {
+            self.discoverable
+        }
+
+        #[zbus(property)]
+        fn set_discoverable(&mut self, discoverable: bool) {
+            self.discoverable = discoverable;
+        }
+
+        #[zbus(property)]
+        fn discoverable_timeout(&self) -> u32 {
+            self.discoverable_timeout
+        }
+
+        #[zbus(property)]
+        fn set_discoverable_timeout(&mut self, discoverable_timeout: u32) {
+            self.discoverable_timeout = discoverable_timeout;
+        }
+    }
+
+    struct AgentManager;
+
+    #[zbus::interface(name = "org.bluez.AgentManager1")]
+    impl AgentManager {
+        fn register_agent(&self, _agent: ObjectPath<'_>, _capability: String) {}
+
+        fn request_default_agent(&self, _agent: ObjectPath<'_>) {}
+
+        fn unregister_agent(&self, _agent: ObjectPath<'_>) {}
+    }
+
+    struct Device {
+        trusted: bool,
+    }
+
+    #[zbus::interface(name = "org.bluez.Device1")]
+    impl Device {
+        #[zbus(property)]
+        fn alias(&self) -> String {
+            "Test iPhone".to_string()
+        }
+
+        #[zbus(property)]
+        fn paired(&self) -> bool {
+            true
+        }
+
+        #[zbus(property)]
+        fn trusted(&self) -> bool {
+            self.trusted
+        }
+
+        #[zbus(property)]
+        fn set_trusted(&mut self, trusted: bool) {
+            self.trusted = trusted;
+        }
+    }
+
+    // answers every notification with this action, or never if it's None
+    struct Notifications {
+        answer: Option<&'static str>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.Notifications")]
+    impl Notifications {
+        #[allow(clippy::too_many_arguments)]
+        async fn notify(
+            &self,
+            #[zbus(signal_emitter)] emitter: zbus::object_server::SignalEmitter<'_>,
+            _app_name: String,
+            _replaces_id: u32,
+            _app_icon: String,
+            _summary: String,
+            _body: String,
+            _actions: Vec<String>,
+            _hints: HashMap<String, OwnedValue>,
+            _expire_timeout: i32,
+        ) -> zbus::fdo::Result<u32> {
+            if let Some(action) = self.answer {
+                Self::action_invoked(&emitter, 1, action).await?;
+            }
+            Ok(1)
+        }
+
+        async fn close_notification(
+            &self,
+            #[zbus(signal_emitter)] emitter: zbus::object_server::SignalEmitter<'_>,
+            id: u32,
+        ) -> zbus::fdo::Result<()> {
+            // closed by a call to CloseNotification
+            Self::notification_closed(&emitter, id, 3).await?;
+            Ok(())
+        }
+
+        #[zbus(signal)]
+        async fn action_invoked(
+            emitter: &zbus::object_server::SignalEmitter<'_>,
+            id: u32,
+            action_key: &str,
+        ) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        async fn notification_closed(
+            emitter: &zbus::object_server::SignalEmitter<'_>,
+            id: u32,
+            reason: u32,
+        ) -> zbus::Result<()>;
+    }
+
+    // both ends of a private connection, with no bus in between
+    async fn connection_pair() -> (zbus::Connection, zbus::Connection) {
+        let (server, client) = std::os::unix::net::UnixStream::pair().unwrap();
+        let (server, client) = futures::join!(
+            zbus::connection::Builder::async_io_unix_stream(server)
+                .server(zbus::Guid::generate())
+                .unwrap()
+                .p2p()
+                .build(),
+            zbus::connection::Builder::async_io_unix_stream(client)
+                .p2p()
+                .build(),
+        );
+        (server.unwrap(), client.unwrap())
+    }
+
+    #[tokio::test]
+    async fn pairs_and_restores_the_adapter() {
+        let (bluez, system) = connection_pair().await;
+        let (_notifications, session) = connection_pair().await;
+        let objects = bluez.object_server();
+        objects.at("/", zbus::fdo::ObjectManager).await.unwrap();
+        objects.at("/org/bluez", AgentManager).await.unwrap();
+        objects
+            .at(
+                "/org/bluez/hci0",
+                Adapter {
+                    pairable: false,
+                    discoverable: true,
+                    discoverable_timeout: 180,
+                },
+            )
+            .await
+            .unwrap();
+
+        // the device pairs once the adapter is made pairable
+        let adapter = objects
+            .interface::<_, Adapter>("/org/bluez/hci0")
+            .await
+            .unwrap();
+        let device_server = bluez.clone();
+        let device = tokio::spawn(async move {
+            while !adapter.get().await.pairable {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            device_server
+                .object_server()
+                .at(
+                    "/org/bluez/hci0/dev_00_11_22_33_44_55",
+                    Device { trusted: false },
+                )
+                .await
+                .unwrap();
+        });
+
+        let paired = pair(&system, &session, BLUEZ, Some("hci0")).await;
+        device.await.unwrap();
+        assert_eq!(paired.unwrap().as_deref(), Some("Test iPhone"));
+
+        let adapter = objects
+            .interface::<_, Adapter>("/org/bluez/hci0")
+            .await
+            .unwrap();
+        let adapter = adapter.get().await;
+        assert!(!adapter.pairable);
+        assert!(adapter.discoverable);
+        assert_eq!(adapter.discoverable_timeout, 180);
+        let device = objects
+            .interface::<_, Device>("/org/bluez/hci0/dev_00_11_22_33_44_55")
+            .await
+            .unwrap();
+        assert!(device.get().await.trusted);
+        assert!(objects.interface::<_, Agent>(AGENT_PATH).await.is_err());
+    }
+
+    const DEVICE_PATH: &str = "/org/bluez/hci0/dev_00_11_22_33_44_55";
+
+    // an agent like pair() registers, on the system connection, with the
+    // stand-ins for BlueZ and the notification server at the other ends
+    async fn agent(
+        answer: Option<&'static str>,
+    ) -> (zbus::Connection, zbus::Connection, zbus::Connection) {
+        let (bluez, system) = connection_pair().await;
+        let (notifications, session) = connection_pair().await;
+        notifications
+            .object_server()
+            .at("/org/freedesktop/Notifications", Notifications { answer })
+            .await
+            .unwrap();
+        bluez
+            .object_server()
+            .at(DEVICE_PATH, Device { trusted: false })
+            .await
+            .unwrap();
+        let agent = Agent {
+            system: system.clone(),
+            session,
+            destination: BLUEZ.to_string(),
+            prompt: Mutex::new(None),
+        };
+        system.object_server().at(AGENT_PATH, agent).await.unwrap();
+        (bluez, system, notifications)
+    }
+
+    async fn request_confirmation(bluez: &zbus::Connection) -> zbus::Result<()> {
+        proxy(bluez, BLUEZ, AGENT_PATH, "org.bluez.Agent1")
+            .await?
+            .call(
+                "RequestConfirmation",
+                &(
+                    ObjectPath::from_static_str_unchecked(DEVICE_PATH),
+                    123456u32,
+                ),
+            )
+            .await
+    }
+
+    fn is_rejected(result: zbus::Result<()>) -> bool {
+        matches!(result, Err(zbus::Error::MethodError(name, _, _)) if name == "org.bluez.Error.Rejected")
+    }
+
+    #[tokio::test]
+    async fn confirms_when_pair_is_chosen() {
+        let (bluez, _system, _notifications) = agent(Some("pair")).await;
+        request_confirmation(&bluez).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_when_reject_is_chosen() {
+        let (bluez, _system, _notifications) = agent(Some("reject")).await;
+        assert!(is_rejected(request_confirmation(&bluez).await));
+    }
+
+    #[tokio::test]
+    async fn cancel_rejects_the_open_prompt() {
+        let (bluez, system, _notifications) = agent(None).await;
+        let agent = system
+            .object_server()
+            .interface::<_, Agent>(AGENT_PATH)
+            .await
+            .unwrap();
+        let request = tokio::spawn({
+            let bluez = bluez.clone();
+            async move { request_confirmation(&bluez).await }
+        });
+        while agent.get().await.prompt.lock().unwrap().is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        proxy(&bluez, BLUEZ, AGENT_PATH, "org.bluez.Agent1")
+            .await
+            .unwrap()
+            .call::<_, _, ()>("Cancel", &())
+            .await
+            .unwrap();
+        assert!(is_rejected(request.await.unwrap()));
+        assert!(agent.get().await.prompt.lock().unwrap().is_none());
+    }
+}